@@ -8,11 +8,27 @@ use clap::{value_t, values_t};
 use log::*;
 use midly;
 
+use crate::draw_engine;
+use crate::hud::Hud;
+use crate::labels::{Fingering, NoteNames};
+use crate::metronome;
+use crate::metronome::CountIn;
 use crate::midi_container::MidiContainer;
+use crate::midi_input::MidiInputListener;
+use crate::midi_ports::Route;
 use crate::midi_sequencer::MidiEvent;
 use crate::midi_sequencer::MidiSequencer;
 use crate::midi_sequencer::RawMidiTuple;
+use crate::mixer;
+use crate::mixer::Mixer;
+use crate::part;
+use crate::part::Part;
+use crate::practice::{TempoTrainer, WaitAction, WaitMode};
+use crate::recorder::Recorder;
+use crate::renderer::{RenderOutput, RenderSettings};
+use crate::scoring::Scoring;
 use crate::scroller::Scroller;
+use crate::tempo_map::{SongPosition, TempoMap};
 use crate::time_controller::TimeListener;
 use crate::time_controller::TimeListenerTrait;
 
@@ -103,12 +119,22 @@ pub struct AppControl {
     state: Option<AppState>,
     midi_fname: String,
     command_list_tracks: bool,
+    command_list_ports: bool,
+    list_json: bool,
+    render: Option<RenderSettings>,
+    output_port: Option<String>,
+    /// Name of the virtual output port to create
+    virtual_port: Option<String>,
+    routes: Vec<Route>,
+    input_port: Option<String>,
     quiet: bool,
     debug: Option<Vec<String>>,
     verbose: usize,
     paused: bool,
     scale_1000: u16,
     pos_us: i64,
+    /// Loop passes of the sequencer already handled
+    loop_passes: u32,
     left_key: u8,
     right_key: u8,
     shift_key: i8,
@@ -116,10 +142,39 @@ pub struct AppControl {
     need_redraw_textures: bool,
     request_events: bool,
     request_keyboard: bool,
-    show_tracks: Vec<usize>,
-    play_tracks: Vec<usize>,
+    show_tracks: Vec<Part>,
+    play_tracks: Vec<Part>,
+    no_play_shown: bool,
+    mixer: Mixer,
     show_events: Option<Vec<RawMidiTuple>>,
+    /// Beat and bar lines of the waterfall
+    beat_grid: Vec<(u64, Option<u64>)>,
+    note_names: Option<NoteNames>,
+    fingers: bool,
+    fingering_fname: Option<String>,
+    fingering: Fingering,
+    font: Option<String>,
+    hud: bool,
+    tempo_map: Option<TempoMap>,
+    duration_us: u64,
+    show_input: bool,
+    input_pressed: Vec<bool>,
+    wait_for_input: bool,
+    wait_mode: Option<WaitMode>,
+    score_tolerance_us: i64,
+    score_json: Option<String>,
+    scoring: Option<Scoring>,
+    recorder: Option<Recorder>,
+    loop_positions: Option<Vec<SongPosition>>,
+    loop_start: Option<i64>,
+    loop_end: Option<i64>,
+    trainer: Option<TempoTrainer>,
+    metronome: bool,
+    count_in_bars: u64,
+    downbeat_key: u8,
+    beat_key: u8,
     sequencer: Option<MidiSequencer>,
+    midi_input: Option<MidiInputListener>,
     scroller: Scroller,
     time_keeper: Option<TimeListener>,
     rx: mpsc::Receiver<WorkerResult>,
@@ -140,28 +195,137 @@ impl AppControl {
         let verbose = matches.occurrences_of("verbose") as usize;
         let shift_key = value_t!(matches, "transpose", i8).unwrap_or_else(|e| e.exit());
         let rd64 = matches.is_present("RD64");
-        let (left_key, right_key): (u8, u8) = if rd64 {
+        let (left_key, right_key): (u8, u8) = if matches.is_present("keys") {
+            let keys = values_t!(matches.values_of("keys"), u8).unwrap_or_else(|e| e.exit());
+            (keys[0].min(keys[1]), keys[0].max(keys[1]))
+        } else if rd64 {
             // RD-64 is A1 to C7
             (21 + 12, 108 - 12)
         } else {
             // 88 note piano range from A0 to C8
             (21, 108)
         };
-        let midi_fname = matches.value_of("MIDI").unwrap().to_string();
+        let midi_fname = matches.value_of("MIDI").unwrap_or("").to_string();
         let list_tracks = matches.is_present("list");
-        let show_tracks = values_t!(matches.values_of("show"), usize).unwrap_or_else(|_| vec![]);
-        let play_tracks = values_t!(matches.values_of("play"), usize).unwrap_or_else(|e| e.exit());
+        let list_ports = matches.is_present("list_ports");
+        let list_json = matches.value_of("format") == Some("json");
+        let output_port = matches.value_of("port").map(|s| s.to_string());
+        let input_port = matches.value_of("input_port").map(|s| s.to_string());
+        let virtual_port = if matches.is_present("virtual") {
+            Some(
+                matches
+                    .value_of("virtual")
+                    .unwrap_or("Rusthesia")
+                    .to_string(),
+            )
+        } else {
+            None
+        };
+        let routes = if matches.is_present("route") {
+            values_t!(matches.values_of("route"), Route).unwrap_or_else(|e| e.exit())
+        } else {
+            vec![]
+        };
+        let show_tracks = if matches.is_present("show") {
+            values_t!(matches.values_of("show"), Part).unwrap_or_else(|e| e.exit())
+        } else {
+            vec![]
+        };
+        let play_tracks = values_t!(matches.values_of("play"), Part).unwrap_or_else(|e| {
+            if list_tracks
+                || list_ports
+                || matches.is_present("render")
+                || matches.is_present("video")
+            {
+                vec![]
+            } else {
+                e.exit()
+            }
+        });
+        let no_play_shown = matches.is_present("no_play_shown");
+        let wait_for_input = matches.is_present("wait");
+        let recorder = matches.value_of("record").map(Recorder::new);
+        let trainer_clean = matches.is_present("trainer_clean");
+        let show_input = matches.is_present("input")
+            || input_port.is_some()
+            || wait_for_input
+            || recorder.is_some()
+            || trainer_clean;
+        let score_tolerance_us =
+            value_t!(matches, "tolerance", i64).unwrap_or_else(|e| e.exit()) * 1_000;
+        let score_json = matches.value_of("score_json").map(|s| s.to_string());
+        let loop_positions = if matches.is_present("loop") {
+            Some(values_t!(matches.values_of("loop"), SongPosition).unwrap_or_else(|e| e.exit()))
+        } else {
+            None
+        };
+        let metronome = matches.is_present("metronome");
+        let count_in_bars = value_t!(matches, "count_in", u64).unwrap_or_else(|e| e.exit());
+        let downbeat_key = value_t!(matches, "downbeat_key", u8).unwrap_or_else(|e| e.exit());
+        let beat_key = value_t!(matches, "beat_key", u8).unwrap_or_else(|e| e.exit());
+        let mut scale_1000 = 1000;
+        let trainer = if matches.is_present("trainer") {
+            let start = value_t!(matches, "trainer", u16).unwrap_or_else(|e| e.exit());
+            let step = value_t!(matches, "trainer_step", u16).unwrap_or_else(|e| e.exit());
+            let target = value_t!(matches, "trainer_target", u16).unwrap_or_else(|e| e.exit());
+            scale_1000 = start.clamp(25, 400) * 10;
+            Some(TempoTrainer::new(
+                step.min(400) * 10,
+                target.clamp(25, 400) * 10,
+                trainer_clean,
+            ))
+        } else {
+            None
+        };
+        let render_output = match (matches.value_of("render"), matches.value_of("video")) {
+            (Some(dir), _) => Some(RenderOutput::Png(dir.to_string())),
+            (None, Some(fname)) => Some(RenderOutput::Video(fname.to_string())),
+            (None, None) => None,
+        };
+        let render = render_output.map(|output| RenderSettings {
+            output,
+            width: value_t!(matches, "width", u32).unwrap_or_else(|e| e.exit()),
+            height: value_t!(matches, "height", u32).unwrap_or_else(|e| e.exit()),
+            fps: value_t!(matches, "fps", u32).unwrap_or_else(|e| e.exit()),
+            lookahead_us: if matches.is_present("lookahead") {
+                let lookahead_s = value_t!(matches, "lookahead", f32).unwrap_or_else(|e| e.exit());
+                Some((lookahead_s * 1_000_000.0) as u64)
+            } else {
+                None
+            },
+            at: if matches.is_present("at") {
+                Some(value_t!(matches, "at", SongPosition).unwrap_or_else(|e| e.exit()))
+            } else {
+                None
+            },
+        });
+        let note_names = if matches.is_present("note_names") {
+            Some(value_t!(matches, "note_names", NoteNames).unwrap_or_else(|e| e.exit()))
+        } else {
+            None
+        };
+        let fingers = matches.is_present("fingers");
+        let fingering_fname = matches.value_of("fingering").map(|s| s.to_string());
+        let font = matches.value_of("font").map(|s| s.to_string());
         let scroller = Scroller::new(5_000_000.0);
         AppControl {
             state: Some(AppState::Check),
             midi_fname,
             command_list_tracks: list_tracks,
+            command_list_ports: list_ports,
+            list_json,
+            render,
+            output_port,
+            virtual_port,
+            routes,
+            input_port,
             quiet,
             debug,
             verbose,
             paused: false,
-            scale_1000: 1000,
+            scale_1000,
             pos_us: 0,
+            loop_passes: 0,
             width: None,
             left_key,
             right_key,
@@ -171,8 +335,36 @@ impl AppControl {
             need_redraw_textures: false,
             show_tracks,
             play_tracks,
+            no_play_shown,
+            mixer: Mixer::new(),
             show_events: None,
+            beat_grid: vec![],
+            note_names,
+            fingers,
+            fingering_fname,
+            fingering: Fingering::new(),
+            font,
+            hud: !matches.is_present("no_hud"),
+            tempo_map: None,
+            duration_us: 0,
+            show_input,
+            input_pressed: vec![false; 128],
+            wait_for_input,
+            wait_mode: None,
+            score_tolerance_us,
+            score_json,
+            scoring: None,
+            recorder,
+            loop_positions,
+            loop_start: None,
+            loop_end: None,
+            trainer,
+            metronome,
+            count_in_bars,
+            downbeat_key,
+            beat_key,
             sequencer: None,
+            midi_input: None,
             scroller,
             time_keeper: None,
             rx,
@@ -220,6 +412,225 @@ impl AppControl {
             }
             self.sequencer = Some(seq);
         }
+        self.seek_wait_mode();
+    }
+    pub fn set_loop_start(&mut self) {
+        self.loop_start = Some(self.pos_us);
+        if self.loop_end.map_or(false, |end| end <= self.pos_us) {
+            self.loop_end = None;
+        }
+        self.update_loop();
+    }
+    pub fn set_loop_end(&mut self) {
+        if self.loop_start.map_or(true, |start| start < self.pos_us) {
+            self.loop_end = Some(self.pos_us);
+            self.update_loop();
+        }
+    }
+    pub fn clear_loop(&mut self) {
+        self.loop_start = None;
+        self.loop_end = None;
+        self.update_loop();
+    }
+    /// Loop is active with end marker set. Without start marker the loop
+    /// starts at the beginning of the song.
+    fn active_loop(&self) -> Option<(i64, i64)> {
+        self.loop_end
+            .map(|end| (self.loop_start.unwrap_or(0).min(end), end))
+    }
+    fn update_loop(&mut self) {
+        let opt_loop = self.active_loop();
+        match opt_loop {
+            Some((start, end)) => info!("Loop from {} us to {} us", start, end),
+            None => info!("Loop cleared"),
+        }
+        if let Some(seq) = self.sequencer.take() {
+            seq.set_loop(opt_loop);
+            self.sequencer = Some(seq);
+        }
+    }
+    /// Resolve the track names of the shown and played parts
+    pub fn resolve_parts(&mut self, track_names: &[Vec<String>]) -> Result<(), String> {
+        self.show_tracks = part::resolve(&self.show_tracks, track_names)?;
+        self.play_tracks = part::resolve(&self.play_tracks, track_names)?;
+        let mut routes = vec![];
+        for route in self.routes.iter() {
+            for part in part::resolve(std::slice::from_ref(&route.part), track_names)?.into_iter() {
+                routes.push(Route {
+                    part,
+                    port: route.port.clone(),
+                });
+            }
+        }
+        self.routes = routes;
+        Ok(())
+    }
+    /// Resolve the loop markers given on the command line, which may need
+    /// the tempo map for bar positions. Playing starts at the loop start.
+    fn resolve_loop_positions(
+        &mut self,
+        tempo_map: &TempoMap,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(positions) = self.loop_positions.take() {
+            let mut markers = vec![];
+            for pos in positions.into_iter() {
+                let pos_us = pos
+                    .to_us(tempo_map)
+                    .ok_or("bar positions need a midi file with metrical timing")?;
+                markers.push(pos_us as i64);
+            }
+            if markers[0] >= markers[1] {
+                return Err("loop end has to be after loop start".into());
+            }
+            self.loop_start = Some(markers[0]);
+            self.loop_end = Some(markers[1]);
+            self.pos_us = markers[0];
+            if let Some(seq) = self.sequencer.take() {
+                seq.set_pos_us(self.pos_us);
+                self.sequencer = Some(seq);
+            }
+            self.update_loop();
+        }
+        Ok(())
+    }
+    pub fn panic(&mut self) {
+        info!("Panic: reset all used channels");
+        if let Some(seq) = self.sequencer.take() {
+            seq.panic();
+            self.sequencer = Some(seq);
+        }
+    }
+    pub fn toggle_metronome(&mut self) {
+        self.metronome = !self.metronome;
+        info!("Metronome: {}", self.metronome);
+        if let Some(seq) = self.sequencer.take() {
+            seq.set_metronome(self.metronome);
+            self.sequencer = Some(seq);
+        }
+    }
+    fn update_mixer(&mut self) {
+        if let Some(seq) = self.sequencer.take() {
+            seq.set_mixer(self.mixer.clone());
+            self.sequencer = Some(seq);
+        }
+    }
+    pub fn toggle_mute(&mut self, trk_idx: usize) {
+        let muted = self.mixer.toggle_mute(trk_idx);
+        info!("Track {} muted: {}", trk_idx, muted);
+        self.update_mixer();
+    }
+    pub fn toggle_solo(&mut self, trk_idx: usize) {
+        let soloed = self.mixer.toggle_solo(trk_idx);
+        info!("Track {} soloed: {}", trk_idx, soloed);
+        self.update_mixer();
+    }
+    /// Change the volume of the shown tracks in steps of 10%, so the
+    /// practised part can be faded out
+    pub fn change_volume(&mut self, louder: bool) {
+        for part in self.show_tracks.iter() {
+            let volume = self.mixer.volume(part);
+            let volume = if louder {
+                100.min(volume + 10)
+            } else {
+                volume.max(10) - 10
+            };
+            info!("Part {:?} volume: {}%", part, volume);
+            self.mixer.set_volume(part, volume);
+        }
+        self.update_mixer();
+    }
+    /// Show or hide a track. The events are reloaded and the waterfall
+    /// is redrawn, while playing continues.
+    pub fn toggle_shown(&mut self, trk_idx: usize) {
+        let shown = mixer::toggle(&mut self.show_tracks, Part::track(trk_idx));
+        info!("Track {} shown: {}", trk_idx, shown);
+        self.request_events = true;
+    }
+    pub fn toggle_played(&mut self, trk_idx: usize) {
+        let played = mixer::toggle(&mut self.play_tracks, Part::track(trk_idx));
+        info!("Track {} played: {}", trk_idx, played);
+        self.request_events = true;
+    }
+    pub fn mixer_text(&self) -> Option<String> {
+        self.mixer.text()
+    }
+    /// Raise the speed by the tempo trainer after a repetition of the loop
+    fn next_repetition(&mut self) {
+        let (start, end) = match self.active_loop() {
+            Some(markers) => markers,
+            None => return,
+        };
+        let clean = self
+            .scoring
+            .as_ref()
+            .map_or(false, |scoring| scoring.is_clean(start, end));
+        let new_scale = match self.trainer.as_ref() {
+            Some(trainer) => trainer.next_scaling_1000(self.scale_1000, clean),
+            None => return,
+        };
+        if new_scale != self.scale_1000 {
+            self.scale_1000 = new_scale;
+            info!("Tempo trainer: new scaling {}", self.scale_1000);
+            if let Some(seq) = self.sequencer.take() {
+                seq.set_scaling_1000(self.scale_1000);
+                self.sequencer = Some(seq);
+            }
+        }
+    }
+    /// Handle the loop passes completed by the sequencer since the last call
+    pub fn check_loop_passes(&mut self) {
+        let passes = match self.time_keeper.as_ref() {
+            Some(time_keeper) => time_keeper.get_loop_passes(),
+            None => return,
+        };
+        if passes == self.loop_passes {
+            return;
+        }
+        self.pos_us = self.time_keeper.as_ref().unwrap().get_pos_us();
+        trace!("Looped back to {} us", self.pos_us);
+        while self.loop_passes != passes {
+            self.loop_passes += 1;
+            self.next_repetition();
+        }
+        self.seek_wait_mode();
+    }
+    /// Remaining beats, while counting in
+    pub fn count_in_beats(&self) -> Option<u32> {
+        let beats = self.time_keeper.as_ref()?.get_count_in_beats();
+        if beats > 0 {
+            Some(beats)
+        } else {
+            None
+        }
+    }
+    pub fn speed_text(&self) -> Option<String> {
+        if self.trainer.is_some() || self.scale_1000 != 1000 {
+            Some(format!("Speed {}%", self.scale_1000 / 10))
+        } else {
+            None
+        }
+    }
+    pub fn toggle_hud(&mut self) {
+        self.hud = !self.hud;
+    }
+    /// Lines of the heads-up display including score and mixer or None,
+    /// if it is hidden
+    pub fn hud_lines(&self, pos_us: i64) -> Option<Vec<String>> {
+        if !self.hud {
+            return None;
+        }
+        let hud = Hud {
+            midi_fname: &self.midi_fname,
+            pos_us,
+            duration_us: self.duration_us,
+            scale_1000: self.scale_1000,
+            shift_key: self.shift_key,
+            paused: self.paused,
+        };
+        let mut lines = hud.lines(self.tempo_map.as_ref());
+        lines.extend(self.score_text());
+        lines.extend(self.mixer_text());
+        Some(lines)
     }
     pub fn tune_up(&mut self, tune_up: bool) {
         self.shift_key = if tune_up {
@@ -259,6 +670,7 @@ impl AppControl {
                 self.sequencer = Some(seq);
                 self.pos_us = self.pos_us + delta as i64;
             }
+            self.seek_wait_mode();
         }
     }
     pub fn is_quiet(&self) -> bool {
@@ -285,10 +697,19 @@ impl AppControl {
     pub fn list_command(&self) -> bool {
         self.command_list_tracks
     }
-    pub fn show_tracks(&self) -> &Vec<usize> {
+    pub fn list_json(&self) -> bool {
+        self.list_json
+    }
+    pub fn take_render_settings(&mut self) -> Option<RenderSettings> {
+        self.render.take()
+    }
+    pub fn list_ports_command(&self) -> bool {
+        self.command_list_ports
+    }
+    pub fn show_tracks(&self) -> &Vec<Part> {
         &self.show_tracks
     }
-    pub fn play_tracks(&self) -> &Vec<usize> {
+    pub fn play_tracks(&self) -> &Vec<Part> {
         &self.play_tracks
     }
     pub fn seq_is_finished(&mut self) -> bool {
@@ -300,25 +721,190 @@ impl AppControl {
             true
         }
     }
+    /// The sequencer thread silences all used channels, when it is closed
+    pub fn close_sequencer(&mut self) {
+        self.sequencer = None;
+    }
     pub fn show_events(&self) -> Option<&Vec<RawMidiTuple>> {
         self.show_events.as_ref()
     }
-    pub fn show_events_len(&self) -> usize {
-        self.show_events
-            .as_ref()
-            .map(|events| events.len())
-            .unwrap_or(0)
+    pub fn input_pressed(&self) -> Option<&[bool]> {
+        self.midi_input.as_ref().map(|_| &self.input_pressed[..])
+    }
+    pub fn note_names(&self) -> Option<NoteNames> {
+        self.note_names
+    }
+    pub fn fingering(&self) -> &Fingering {
+        &self.fingering
+    }
+    /// The font given on the command line or a system font
+    pub fn font_path(&self) -> Option<std::path::PathBuf> {
+        match self.font {
+            Some(ref font) => Some(font.into()),
+            None => draw_engine::find_font(),
+        }
+    }
+    /// Finger numbers from the fingering file or from the midi file
+    pub fn read_fingering<'m>(
+        &self,
+        container: &'m MidiContainer<'m>,
+        tempo_map: &TempoMap,
+    ) -> Result<Fingering, Box<dyn std::error::Error>> {
+        if let Some(ref fname) = self.fingering_fname {
+            let text = std::fs::read_to_string(fname)?;
+            Ok(Fingering::from_text(&text, tempo_map)?)
+        } else if self.fingers {
+            let fingering = Fingering::from_midi(container);
+            if fingering.is_empty() {
+                warn!("No finger numbers found in text or lyric events");
+            }
+            Ok(fingering)
+        } else {
+            Ok(Fingering::new())
+        }
+    }
+    pub fn beat_grid(&self) -> &[(u64, Option<u64>)] {
+        &self.beat_grid
     }
     pub fn create_connected_sequencer(
         &mut self,
         exit_on_eof: bool,
     ) -> Result<(), Box<dyn std::error::Error>> {
+        let buf = std::fs::read(&self.midi_fname)?;
+        let smf_buf = midly::Smf::parse(&buf)
+            .map_err(|e| Error::new(ErrorKind::Other, format!("{:?}", e)))?;
+        let container = MidiContainer::from_buf(&smf_buf)?;
+        self.resolve_parts(&container.track_names())?;
+
         let mut sequencer = MidiSequencer::new(exit_on_eof);
-        sequencer.connect()?;
+        if let Some(port_name) = self.virtual_port.as_ref() {
+            sequencer.connect_virtual(port_name)?;
+        } else {
+            sequencer.connect(self.output_port.as_deref())?;
+        }
+        sequencer.connect_routes(&self.routes)?;
+        sequencer.set_scaling_1000(self.scale_1000);
         self.time_keeper = Some(sequencer.get_new_listener());
+        self.loop_passes = 0;
+
+        let tempo_map = container.tempo_map();
+        let end_tick = container
+            .iter()
+            .map(|(tick, _, _)| tick)
+            .last()
+            .unwrap_or(0);
+        self.beat_grid = tempo_map.grid(end_tick);
+        self.duration_us = tempo_map.tick_to_us(end_tick);
+        self.fingering = self.read_fingering(&container, &tempo_map)?;
+        let clicks = metronome::clicks(&tempo_map, end_tick, self.downbeat_key, self.beat_key);
+        if clicks.is_empty() {
+            warn!("No metronome for midi files with timecode");
+        }
+        sequencer.set_clicks(clicks);
+        sequencer.set_metronome(self.metronome);
+        if self.count_in_bars > 0 {
+            sequencer.set_count_in(Some(CountIn {
+                bars: self.count_in_bars,
+                tempo_map: tempo_map.clone(),
+                downbeat_key: self.downbeat_key,
+                beat_key: self.beat_key,
+            }));
+        }
+
         self.sequencer = Some(sequencer);
+        self.resolve_loop_positions(&tempo_map)?;
+        self.tempo_map = Some(tempo_map);
+        Ok(())
+    }
+    pub fn connect_midi_input(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.show_input {
+            let time_listener = self.time_keeper.as_ref().unwrap().clone();
+            let opt_port = self.input_port.as_deref();
+            self.midi_input = Some(MidiInputListener::connect(time_listener, opt_port)?);
+        }
         Ok(())
     }
+    pub fn process_midi_input(&mut self) {
+        if let Some(midi_input) = self.midi_input.as_ref() {
+            while let Some((pos_us, evt)) = midi_input.try_recv() {
+                match evt {
+                    MidiEvent::NoteOn(_channel, key, pressure) if pressure > 0 => {
+                        self.input_pressed[key as usize & 0x7f] = true;
+                        if let Some(wait_mode) = self.wait_mode.as_mut() {
+                            wait_mode.key_pressed(pos_us, key);
+                        }
+                        if let Some(scoring) = self.scoring.as_mut() {
+                            scoring.note_played(pos_us, key, pressure);
+                        }
+                    }
+                    MidiEvent::NoteOn(_channel, key, _) | MidiEvent::NoteOff(_channel, key, _) => {
+                        self.input_pressed[key as usize & 0x7f] = false;
+                    }
+                    _ => (),
+                }
+                if let Some(recorder) = self.recorder.as_mut() {
+                    recorder.record(pos_us, evt);
+                }
+            }
+        }
+    }
+    fn seek_wait_mode(&mut self) {
+        if let Some(wait_mode) = self.wait_mode.as_mut() {
+            wait_mode.seek(self.pos_us);
+        }
+        if let Some(scoring) = self.scoring.as_mut() {
+            scoring.seek(self.pos_us);
+        }
+    }
+    pub fn finish_recording(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(recorder) = self.recorder.as_ref() {
+            recorder.write(&self.midi_fname)?;
+        }
+        Ok(())
+    }
+    pub fn score_text(&self) -> Option<String> {
+        self.scoring.as_ref().map(|scoring| scoring.overlay_text())
+    }
+    pub fn finish_scoring(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(scoring) = self.scoring.as_ref() {
+            scoring.print_summary();
+            if let Some(fname) = self.score_json.as_ref() {
+                let json = serde_json::to_string_pretty(&scoring.to_json())?;
+                std::fs::write(fname, json)?;
+            }
+        }
+        Ok(())
+    }
+    fn update_wait_mode(&mut self) {
+        if self.paused {
+            return;
+        }
+        let action = match self.wait_mode.as_mut() {
+            Some(wait_mode) => wait_mode.update(self.pos_us),
+            None => return,
+        };
+        match action {
+            WaitAction::Hold(hold_pos_us) => {
+                if !self.time_keeper.as_ref().unwrap().is_running() {
+                    return;
+                }
+                if let Some(seq) = self.sequencer.take() {
+                    trace!("Wait for keys at {} us", hold_pos_us);
+                    seq.stop();
+                    seq.set_pos_us(hold_pos_us);
+                    self.sequencer = Some(seq);
+                    self.pos_us = hold_pos_us;
+                }
+            }
+            WaitAction::Resume => {
+                if let Some(seq) = self.sequencer.take() {
+                    seq.resume(self.pos_us);
+                    self.sequencer = Some(seq);
+                }
+            }
+            WaitAction::None => (),
+        }
+    }
     pub fn get_pos_us_after(&mut self, dt_us: u32) -> i64 {
         let rem_dur = Duration::new(0, dt_us * 1_000);
         self.time_keeper.as_ref().unwrap().get_pos_us_after(rem_dur)
@@ -326,7 +912,7 @@ impl AppControl {
     pub fn get_keyboard(&self) -> Option<&piano_keyboard::Keyboard2d> {
         self.keyboard.as_ref()
     }
-    fn build_keyboard(
+    pub fn build_keyboard(
         width: u16,
         left_key: u8,
         right_key: u8,
@@ -342,8 +928,9 @@ impl AppControl {
         left_key: u8,
         right_key: u8,
         shift_key: i8,
-        show_tracks: Vec<usize>,
-        play_tracks: Vec<usize>,
+        show_tracks: Vec<Part>,
+        play_tracks: Vec<Part>,
+        no_play_shown: bool,
     ) -> Result<(Vec<RawMidiTuple>, Vec<RawMidiTuple>), std::io::Error> {
         let buf = std::fs::read(midi_fname)?;
         let smf_buf = midly::Smf::parse(&buf)
@@ -352,36 +939,48 @@ impl AppControl {
         let show_events = container
             .iter()
             .timed(&container.header().timing)
-            .filter(|(_time_us, trk, _evt)| show_tracks.contains(trk))
             .filter_map(|(time_us, trk, evt)| match evt {
-                midly::EventKind::Midi { channel, message } => transposed_message(
-                    time_us,
-                    trk,
-                    channel.as_int(),
-                    &message,
-                    false,
-                    shift_key,
-                    left_key,
-                    right_key,
-                ),
+                midly::EventKind::Midi { channel, message }
+                    if part::is_selected(&show_tracks, trk, channel.as_int()) =>
+                {
+                    transposed_message(
+                        time_us,
+                        trk,
+                        channel.as_int(),
+                        &message,
+                        false,
+                        shift_key,
+                        left_key,
+                        right_key,
+                    )
+                }
                 _ => None,
             })
             .collect::<Vec<_>>();
         let play_events = container
             .iter()
             .timed(&container.header().timing)
-            .filter(|(_time_us, trk, _evt)| play_tracks.contains(trk))
             .filter_map(|(time_us, trk, evt)| match evt {
-                midly::EventKind::Midi { channel, message } => transposed_message(
-                    time_us,
-                    trk,
-                    channel.as_int(),
-                    &message,
-                    true,
-                    shift_key,
-                    left_key,
-                    right_key,
-                ),
+                midly::EventKind::Midi { channel, message }
+                    if part::is_played(
+                        &play_tracks,
+                        &show_tracks,
+                        no_play_shown,
+                        trk,
+                        channel.as_int(),
+                    ) =>
+                {
+                    transposed_message(
+                        time_us,
+                        trk,
+                        channel.as_int(),
+                        &message,
+                        true,
+                        shift_key,
+                        left_key,
+                        right_key,
+                    )
+                }
                 _ => None,
             })
             .inspect(|e| trace!("{:?}", e))
@@ -416,6 +1015,9 @@ impl AppControl {
             let shift_key = self.shift_key;
             let show_tracks = self.show_tracks.clone();
             let play_tracks = self.play_tracks.clone();
+            let no_play_shown = self.no_play_shown;
+            // Changes while loading request the events again
+            self.request_events = false;
             let jh = thread::spawn(move || {
                 let res = AppControl::read_midi_file(
                     &midi_fname,
@@ -424,6 +1026,7 @@ impl AppControl {
                     shift_key,
                     show_tracks,
                     play_tracks,
+                    no_play_shown,
                 );
                 trace!(target: WK, "Send events to main");
                 tx.send(WorkerResult::EventsLoaded(res)).unwrap();
@@ -435,6 +1038,7 @@ impl AppControl {
         if let Some(time_keeper) = self.time_keeper.as_ref() {
             self.pos_us = time_keeper.get_pos_us();
         }
+        self.check_loop_passes();
         let th_result = self.rx.try_recv();
         match th_result {
             Ok(WorkerResult::EventsLoaded(Ok((show_events, play_events)))) => {
@@ -445,14 +1049,31 @@ impl AppControl {
                     .join()
                     .expect("something went wrong with worker thread");
                 trace!(target: WK, "Join worker done");
+                // Wait mode and scoring are built once and keep their state
+                // on every reload of the events
+                if let Some(wait_mode) = self.wait_mode.as_mut() {
+                    wait_mode.reload(&show_events, self.pos_us);
+                } else if self.wait_for_input {
+                    let mut wait_mode = WaitMode::new(&show_events);
+                    wait_mode.seek(self.pos_us);
+                    self.wait_mode = Some(wait_mode);
+                }
+                if let Some(scoring) = self.scoring.as_mut() {
+                    scoring.reload(&show_events, self.pos_us);
+                } else if self.show_input {
+                    let mut scoring = Scoring::new(&show_events, self.score_tolerance_us);
+                    scoring.seek(self.pos_us);
+                    self.scoring = Some(scoring);
+                }
                 self.show_events = Some(show_events);
                 if let Some(seq) = self.sequencer.take() {
                     seq.set_midi_data(play_events);
-                    seq.play(self.pos_us);
+                    if !self.paused {
+                        seq.resume(self.pos_us);
+                    }
                     self.sequencer = Some(seq);
                 }
                 self.need_redraw_textures = true;
-                self.request_events = self.show_events.is_none();
             }
             Ok(WorkerResult::KeyboardBuilt(Ok(keyboard))) => {
                 trace!(target: WK, "Keyboard built");
@@ -470,6 +1091,11 @@ impl AppControl {
             Ok(WorkerResult::KeyboardBuilt(Err(_))) => (),
             Err(_) => (),
         };
+        self.process_midi_input();
+        if let Some(scoring) = self.scoring.as_mut() {
+            scoring.update(self.pos_us);
+        }
+        self.update_wait_mode();
         debug!("AppState: {:?}", self.state);
         let s = match self.state.take() {
             Some(AppState::Check) => {
@@ -522,7 +1148,7 @@ impl AppControl {
     pub fn play_midi_data(&mut self, play_events: Vec<RawMidiTuple>) {
         if let Some(seq) = self.sequencer.take() {
             seq.set_midi_data(play_events);
-            seq.play(0);
+            seq.play(self.pos_us);
             self.sequencer = Some(seq);
         }
     }
@@ -0,0 +1,276 @@
+use crate::midi_sequencer::{MidiEvent, RawMidiTuple};
+
+const BANK_SELECT_MSB: u8 = 0;
+const BANK_SELECT_LSB: u8 = 32;
+const DATA_ENTRY_MSB: u8 = 6;
+const DATA_ENTRY_LSB: u8 = 38;
+const DATA_INCREMENT: u8 = 96;
+const DATA_DECREMENT: u8 = 97;
+const NRPN_LSB: u8 = 98;
+const NRPN_MSB: u8 = 99;
+const RPN_LSB: u8 = 100;
+const RPN_MSB: u8 = 101;
+const RESET_ALL_CONTROLLERS: u8 = 121;
+/// Controllers not reset by reset all controllers as of GM recommended practice
+const NOT_RESET: [u8; 19] = [
+    0, 7, 10, 32, 70, 71, 72, 73, 74, 75, 76, 77, 78, 79, 91, 92, 93, 94, 95,
+];
+/// Controllers from here on are channel mode messages
+const CHANNEL_MODE: u8 = 120;
+
+/// Parameter number as (msb controller, msb, lsb) for RPN and NRPN
+type ParamNumber = (u8, u8, u8);
+
+struct ChannelState {
+    program: Option<u8>,
+    controllers: Vec<Option<u8>>,
+    /// Selected parameter for data entry
+    param: Option<ParamNumber>,
+    /// Data entry msb and lsb of all parameters in order of first use
+    params: Vec<(ParamNumber, Option<u8>, Option<u8>)>,
+    pitch_bend: Option<u16>,
+    pressure: Option<u8>,
+}
+impl ChannelState {
+    fn new() -> ChannelState {
+        ChannelState {
+            program: None,
+            controllers: vec![None; 128],
+            param: None,
+            params: vec![],
+            pitch_bend: None,
+            pressure: None,
+        }
+    }
+    fn controller(&mut self, control: u8, value: u8) {
+        let control = control & 0x7f;
+        match control {
+            RESET_ALL_CONTROLLERS => {
+                for (control, value) in self.controllers.iter_mut().enumerate() {
+                    if !NOT_RESET.contains(&(control as u8)) {
+                        *value = None;
+                    }
+                }
+                self.param = None;
+                self.pitch_bend = None;
+                self.pressure = None;
+            }
+            DATA_ENTRY_MSB | DATA_ENTRY_LSB => {
+                if let Some(param) = self.param {
+                    let pos = match self.params.iter().position(|p| p.0 == param) {
+                        Some(pos) => pos,
+                        None => {
+                            self.params.push((param, None, None));
+                            self.params.len() - 1
+                        }
+                    };
+                    if control == DATA_ENTRY_MSB {
+                        self.params[pos].1 = Some(value);
+                    } else {
+                        self.params[pos].2 = Some(value);
+                    }
+                }
+            }
+            DATA_INCREMENT | DATA_DECREMENT => (),
+            _ => {
+                self.controllers[control as usize] = Some(value);
+                let selected = |msb, lsb| match (&self.controllers[msb], &self.controllers[lsb]) {
+                    (Some(m), Some(l)) => Some((msb as u8, *m, *l)),
+                    _ => None,
+                };
+                match control {
+                    NRPN_MSB | NRPN_LSB => {
+                        self.param = selected(NRPN_MSB as usize, NRPN_LSB as usize)
+                    }
+                    RPN_MSB | RPN_LSB => self.param = selected(RPN_MSB as usize, RPN_LSB as usize),
+                    _ => (),
+                }
+            }
+        }
+    }
+    fn events(&self, channel: u8) -> Vec<MidiEvent> {
+        let mut events = vec![];
+        let cc = |control: u8| {
+            self.controllers[control as usize]
+                .map(|value| MidiEvent::Controller(channel, control, value))
+        };
+        // Bank has to be selected before the program change
+        events.extend(cc(BANK_SELECT_MSB));
+        events.extend(cc(BANK_SELECT_LSB));
+        events.extend(
+            self.program
+                .map(|program| MidiEvent::ProgramChange(channel, program)),
+        );
+        for control in 0..CHANNEL_MODE {
+            match control {
+                BANK_SELECT_MSB | BANK_SELECT_LSB => (),
+                NRPN_LSB | NRPN_MSB | RPN_LSB | RPN_MSB => (),
+                _ => events.extend(cc(control)),
+            }
+        }
+        for ((msb_control, msb, lsb), data_msb, data_lsb) in self.params.iter() {
+            events.push(MidiEvent::Controller(channel, *msb_control, *msb));
+            events.push(MidiEvent::Controller(channel, *msb_control - 1, *lsb));
+            events.extend(
+                data_msb.map(|value| MidiEvent::Controller(channel, DATA_ENTRY_MSB, value)),
+            );
+            events.extend(
+                data_lsb.map(|value| MidiEvent::Controller(channel, DATA_ENTRY_LSB, value)),
+            );
+        }
+        // Restore the selected parameter
+        let selection = match self.param {
+            Some((NRPN_MSB, _, _)) => [RPN_MSB, RPN_LSB, NRPN_MSB, NRPN_LSB],
+            _ => [NRPN_MSB, NRPN_LSB, RPN_MSB, RPN_LSB],
+        };
+        for control in selection.iter() {
+            events.extend(cc(*control));
+        }
+        events.extend(
+            self.pitch_bend
+                .map(|bend| MidiEvent::PitchBend(channel, bend)),
+        );
+        events.extend(
+            self.pressure
+                .map(|pressure| MidiEvent::ChannelAftertouch(channel, pressure)),
+        );
+        events
+    }
+}
+
+/// Program, controllers, pitch bend and channel pressure of all channels
+/// after the given events. Sending these before starting to play in the
+/// middle of a song restores the state as if played from the beginning.
+pub fn chase_state<'a, I>(events: I) -> Vec<MidiEvent>
+where
+    I: IntoIterator<Item = &'a RawMidiTuple>,
+{
+    let mut channels = (0..16).map(|_| ChannelState::new()).collect::<Vec<_>>();
+    for (_, _, evt) in events {
+        match *evt {
+            MidiEvent::Controller(channel, control, value) => {
+                channels[channel as usize & 0x0f].controller(control, value)
+            }
+            MidiEvent::ProgramChange(channel, program) => {
+                channels[channel as usize & 0x0f].program = Some(program)
+            }
+            MidiEvent::PitchBend(channel, bend) => {
+                channels[channel as usize & 0x0f].pitch_bend = Some(bend)
+            }
+            MidiEvent::ChannelAftertouch(channel, pressure) => {
+                channels[channel as usize & 0x0f].pressure = Some(pressure)
+            }
+            MidiEvent::NoteOn(..) | MidiEvent::NoteOff(..) | MidiEvent::Aftertouch(..) => (),
+        }
+    }
+    channels
+        .iter()
+        .enumerate()
+        .flat_map(|(channel, state)| state.events(channel as u8))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chase::chase_state;
+    use crate::midi_sequencer::MidiEvent;
+    use crate::midi_sequencer::MidiEvent::*;
+
+    #[test]
+    fn test_01() {
+        let events = vec![
+            (0, 1, ProgramChange(0, 5)),
+            (0, 1, Controller(0, 7, 100)),
+            (0, 1, Controller(0, 32, 1)),
+            (0, 1, Controller(0, 0, 2)),
+            (0, 2, ProgramChange(1, 40)),
+            (10, 1, NoteOn(0, 60, 64)),
+            (20, 1, Controller(0, 64, 127)),
+            (30, 1, PitchBend(0, 0x2100)),
+            (40, 1, NoteOff(0, 60, 0)),
+            (50, 1, Controller(0, 7, 90)),
+            (60, 1, ChannelAftertouch(0, 30)),
+            (70, 1, ProgramChange(0, 6)),
+        ];
+        assert_eq!(
+            chase_state(&events),
+            vec![
+                Controller(0, 0, 2),
+                Controller(0, 32, 1),
+                ProgramChange(0, 6),
+                Controller(0, 7, 90),
+                Controller(0, 64, 127),
+                PitchBend(0, 0x2100),
+                ChannelAftertouch(0, 30),
+                ProgramChange(1, 40),
+            ]
+        );
+        assert_eq!(
+            chase_state(&events[..5]),
+            vec![
+                Controller(0, 0, 2),
+                Controller(0, 32, 1),
+                ProgramChange(0, 5),
+                Controller(0, 7, 100),
+                ProgramChange(1, 40),
+            ]
+        );
+        assert_eq!(chase_state(&[]), Vec::<MidiEvent>::new());
+    }
+
+    #[test]
+    fn test_02() {
+        // Pitch bend range and fine tuning, then reset of all controllers
+        // and a new pitch bend range
+        let events = vec![
+            (0, 1, Controller(2, 101, 0)),
+            (0, 1, Controller(2, 100, 0)),
+            (0, 1, Controller(2, 6, 12)),
+            (0, 1, Controller(2, 100, 1)),
+            (0, 1, Controller(2, 6, 64)),
+            (0, 1, Controller(2, 38, 0)),
+            (0, 1, Controller(2, 101, 127)),
+            (0, 1, Controller(2, 100, 127)),
+            (0, 1, Controller(2, 10, 30)),
+            (0, 1, PitchBend(2, 0x1000)),
+        ];
+        assert_eq!(
+            chase_state(&events),
+            vec![
+                Controller(2, 10, 30),
+                Controller(2, 101, 0),
+                Controller(2, 100, 0),
+                Controller(2, 6, 12),
+                Controller(2, 101, 0),
+                Controller(2, 100, 1),
+                Controller(2, 6, 64),
+                Controller(2, 38, 0),
+                Controller(2, 101, 127),
+                Controller(2, 100, 127),
+                PitchBend(2, 0x1000),
+            ]
+        );
+        let mut events = events;
+        events.push((10, 1, ProgramChange(2, 3)));
+        events.push((10, 1, Controller(2, 121, 0)));
+        events.push((10, 1, Controller(2, 101, 0)));
+        events.push((10, 1, Controller(2, 100, 0)));
+        events.push((10, 1, Controller(2, 6, 2)));
+        assert_eq!(
+            chase_state(&events),
+            vec![
+                ProgramChange(2, 3),
+                Controller(2, 10, 30),
+                Controller(2, 101, 0),
+                Controller(2, 100, 0),
+                Controller(2, 6, 2),
+                Controller(2, 101, 0),
+                Controller(2, 100, 1),
+                Controller(2, 6, 64),
+                Controller(2, 38, 0),
+                Controller(2, 101, 0),
+                Controller(2, 100, 0),
+            ]
+        );
+    }
+}
@@ -1,12 +1,30 @@
-//use font_kit;
+use std::path::PathBuf;
+
 use log::*;
 use sdl2::gfx::primitives::DrawRenderer;
 use sdl2::pixels::Color;
 
 use piano_keyboard;
 
+use crate::labels::{Fingering, NoteNames};
 use crate::midi_sequencer;
 
+/// Keyboard textures by texture index. The waterfall textures follow them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyTexture {
+    Unpressed = 0,
+    Pressed = 1,
+    InputCorrect = 2,
+    InputWrong = 3,
+}
+pub const KEY_TEXTURES: [KeyTexture; 4] = [
+    KeyTexture::Unpressed,
+    KeyTexture::Pressed,
+    KeyTexture::InputCorrect,
+    KeyTexture::InputWrong,
+];
+pub const WATERFALL_TEXTURE: usize = KEY_TEXTURES.len();
+
 #[derive(Debug, PartialEq)]
 pub enum DrawCommand {
     CopyToScreen {
@@ -43,18 +61,118 @@ fn trk2col(trk: usize, key: u8) -> Color {
     }
 }
 
-pub fn draw_keyboard(
-    keyboard: &piano_keyboard::Keyboard2d,
+/// Path of a sans serif system font
+pub fn find_font() -> Option<PathBuf> {
+    let handle = font_kit::source::SystemSource::new()
+        .select_best_match(
+            &[font_kit::family_name::FamilyName::SansSerif],
+            &font_kit::properties::Properties::new(),
+        )
+        .ok()?;
+    match handle {
+        font_kit::handle::Handle::Path { path, .. } => Some(path),
+        font_kit::handle::Handle::Memory { .. } => None,
+    }
+}
+
+/// Point size of the label font fitting to the width of the white keys
+pub fn label_font_size(keyboard: &piano_keyboard::Keyboard2d) -> u16 {
+    let white_keys = keyboard.white_keys(false);
+    let width = white_keys.first().map_or(20, |r| r.width);
+    (width / 2).clamp(6, 24)
+}
+
+/// Note names and finger numbers drawn into the notes and onto the keys.
+/// Without font no text is drawn.
+pub struct Labels<'a, C> {
+    pub font: Option<&'a sdl2::ttf::Font<'a, 'a>>,
+    pub texture_creator: &'a sdl2::render::TextureCreator<C>,
+    pub note_names: Option<NoteNames>,
+    pub fingering: &'a Fingering,
+    /// Transposition of the shown notes against the fingering
+    pub shift_key: i8,
+}
+impl<'a, C> Labels<'a, C> {
+    /// Draws the text centered at the bottom of rec, if it fits into rec.
+    /// Returns the height of the text drawn.
+    fn draw<T: sdl2::render::RenderTarget>(
+        &self,
+        canvas: &mut sdl2::render::Canvas<T>,
+        text: &str,
+        col: Color,
+        rec: sdl2::rect::Rect,
+    ) -> Option<u32> {
+        let font = self.font?;
+        let (width, height) = font.size_of(text).ok()?;
+        if width > rec.width() || height > rec.height() {
+            return None;
+        }
+        let surface = font.render(text).blended(col).ok()?;
+        let texture = self
+            .texture_creator
+            .create_texture_from_surface(&surface)
+            .ok()?;
+        let x = rec.x() + (rec.width() - width) as i32 / 2;
+        let dst_rec = sdl2::rect::Rect::new(x, rec.bottom() - height as i32, width, height);
+        canvas.copy(&texture, None, dst_rec).ok()?;
+        Some(height)
+    }
+}
+
+/// Heads-up display with the lines of text in the top right corner. Without
+/// font the 8x8 pixel font is used.
+pub fn draw_hud<C>(
     canvas: &mut sdl2::render::Canvas<sdl2::video::Window>,
-    pressed: bool,
+    lines: &[String],
+    font: Option<&sdl2::ttf::Font>,
+    texture_creator: &sdl2::render::TextureCreator<C>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let col = Color::RGB(255, 255, 255);
+    let mut sizes = vec![];
+    for line in lines.iter() {
+        sizes.push(match font {
+            Some(font) => font.size_of(line)?,
+            None => (8 * line.len() as u32, 8),
+        });
+    }
+    let line_height = sizes.iter().map(|(_, h)| *h).max().unwrap_or(0) + 2;
+    let width = sizes.iter().map(|(w, _)| *w).max().unwrap_or(0) + 10;
+    let height = line_height * lines.len() as u32 + 8;
+    let x = canvas.viewport().width() as i32 - width as i32 - 10;
+    canvas.set_blend_mode(sdl2::render::BlendMode::Blend);
+    canvas.set_draw_color(Color::RGBA(0, 0, 0, 160));
+    canvas.fill_rect(sdl2::rect::Rect::new(x, 10, width, height))?;
+    canvas.set_blend_mode(sdl2::render::BlendMode::None);
+    for (i, (line, (w, h))) in lines.iter().zip(sizes).enumerate() {
+        // Right aligned
+        let line_x = x + width as i32 - 5 - w as i32;
+        let line_y = 14 + (i as u32 * line_height) as i32;
+        match font {
+            Some(font) => {
+                let surface = font.render(line).blended(col)?;
+                let texture = texture_creator.create_texture_from_surface(&surface)?;
+                canvas.copy(&texture, None, sdl2::rect::Rect::new(line_x, line_y, w, h))?;
+            }
+            None => canvas.string(line_x as i16, line_y as i16, line, col)?,
+        }
+    }
+    Ok(())
+}
+
+pub fn draw_keyboard<T: sdl2::render::RenderTarget, C>(
+    keyboard: &piano_keyboard::Keyboard2d,
+    canvas: &mut sdl2::render::Canvas<T>,
+    key_texture: KeyTexture,
+    labels: &Labels<C>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     canvas.set_draw_color(sdl2::pixels::Color::RGB(100, 100, 100));
     canvas.clear();
     //let rec = canvas.viewport();
-    let (col_white, col_black) = if pressed {
-        (Color::RGB(100, 255, 255), Color::RGB(50, 150, 150))
-    } else {
-        (Color::RGB(200, 200, 200), Color::RGB(0, 0, 0))
+    let (col_white, col_black) = match key_texture {
+        KeyTexture::Unpressed => (Color::RGB(200, 200, 200), Color::RGB(0, 0, 0)),
+        KeyTexture::Pressed => (Color::RGB(100, 255, 255), Color::RGB(50, 150, 150)),
+        KeyTexture::InputCorrect => (Color::RGB(100, 255, 100), Color::RGB(50, 150, 50)),
+        KeyTexture::InputWrong => (Color::RGB(255, 100, 100), Color::RGB(150, 50, 50)),
     };
 
     for (col, rects) in vec![
@@ -74,6 +192,21 @@ pub fn draw_keyboard(
             canvas.fill_rect(rec)?;
         }
     }
+    if let Some(note_names) = labels.note_names {
+        // The names fit only onto the white keys
+        for (i, el) in keyboard.iter().enumerate() {
+            if let piano_keyboard::Element::WhiteKey { wide: ref r, .. } = *el {
+                let key = keyboard.left_white_key + i as u8;
+                let rec = sdl2::rect::Rect::new(
+                    r.x as i32,
+                    r.y as i32,
+                    r.width as u32,
+                    (r.height as u32).saturating_sub(2),
+                );
+                labels.draw(canvas, &note_names.name(key), Color::RGB(60, 60, 60), rec);
+            }
+        }
+    }
     Ok(())
 }
 
@@ -82,6 +215,7 @@ pub fn get_pressed_key_rectangles(
     height_offset: u32,
     pos_us: i64,
     show_events: &Vec<(u64, usize, midi_sequencer::MidiEvent)>,
+    opt_input_pressed: Option<&[bool]>,
 ) -> Vec<DrawCommand> {
     let nr_of_keys = keyboard.right_white_key - keyboard.left_white_key + 1;
     let mut pressed = vec![0; nr_of_keys as usize];
@@ -113,51 +247,113 @@ pub fn get_pressed_key_rectangles(
     }
 
     let mut highlight = vec![];
-    for (el, is_pressed) in keyboard.iter().zip(pressed.iter()) {
-        if *is_pressed > 0 {
-            let rects = match *el {
-                piano_keyboard::Element::WhiteKey {
-                    wide: ref r1,
-                    small: ref r2,
-                    blind: Some(ref r3),
-                } => vec![r1, r2, r3],
-                piano_keyboard::Element::WhiteKey {
-                    wide: ref r1,
-                    small: ref r2,
-                    blind: None,
-                } => vec![r1, r2],
-                piano_keyboard::Element::BlackKey(ref r1) => vec![r1],
+    for (i, (el, is_pressed)) in keyboard.iter().zip(pressed.iter()).enumerate() {
+        // Keys pressed on the midi input are shown as correct or wrong,
+        // while the expected, but not pressed keys remain highlighted.
+        let key = left_key as usize + i;
+        let key_texture = match (*is_pressed > 0, opt_input_pressed.map(|p| p[key])) {
+            (true, Some(true)) => KeyTexture::InputCorrect,
+            (false, Some(true)) => KeyTexture::InputWrong,
+            (true, _) => KeyTexture::Pressed,
+            (false, _) => continue,
+        };
+        let rects = match *el {
+            piano_keyboard::Element::WhiteKey {
+                wide: ref r1,
+                small: ref r2,
+                blind: Some(ref r3),
+            } => vec![r1, r2, r3],
+            piano_keyboard::Element::WhiteKey {
+                wide: ref r1,
+                small: ref r2,
+                blind: None,
+            } => vec![r1, r2],
+            piano_keyboard::Element::BlackKey(ref r1) => vec![r1],
+        };
+        for r in rects.into_iter() {
+            let src_rect =
+                sdl2::rect::Rect::new(r.x as i32, r.y as i32, r.width as u32, r.height as u32);
+            let dst_rect = sdl2::rect::Rect::new(
+                r.x as i32,
+                (r.y as u32 + height_offset) as i32,
+                r.width as u32,
+                r.height as u32,
+            );
+            let cmd = DrawCommand::CopyToScreen {
+                src_texture: key_texture as usize,
+                src_rect,
+                dst_rect,
             };
-            for r in rects.into_iter() {
-                let src_rect =
-                    sdl2::rect::Rect::new(r.x as i32, r.y as i32, r.width as u32, r.height as u32);
-                let dst_rect = sdl2::rect::Rect::new(
-                    r.x as i32,
-                    (r.y as u32 + height_offset) as i32,
-                    r.width as u32,
-                    r.height as u32,
-                );
-                let cmd = DrawCommand::CopyToScreen {
-                    src_texture: 1,
-                    src_rect,
-                    dst_rect,
-                };
-                highlight.push(cmd);
-            }
+            highlight.push(cmd);
         }
     }
     highlight
 }
 
-pub fn draw_waterfall(
+/// Bar lines with the bar number and lighter beat lines below the notes
+fn draw_grid<T: sdl2::render::RenderTarget>(
+    canvas: &mut sdl2::render::Canvas<T>,
+    bottom_row: u32,
+    net_rows: u32,
+    overlap: u32,
+    rows_per_s: u32,
+    grid: &[(u64, Option<u64>)],
+) {
+    let width = canvas.viewport().width() as i32;
+    let top_row = (bottom_row + net_rows + overlap - 1) as i64;
+    // The bar number is written above the line and may reach into this texture
+    let text_rows = 10;
+    for (time, opt_bar) in grid.iter() {
+        let row = (time * rows_per_s as u64 / 1_000_000) as i64;
+        if row > top_row || row + text_rows < bottom_row as i64 {
+            continue;
+        }
+        let tex_y = (top_row - row) as i32; // flip
+        let col = match opt_bar {
+            Some(_) => Color::RGB(160, 160, 160),
+            None => Color::RGB(120, 120, 120),
+        };
+        canvas.set_draw_color(col);
+        canvas
+            .draw_line(
+                sdl2::rect::Point::new(0, tex_y),
+                sdl2::rect::Point::new(width - 1, tex_y),
+            )
+            .unwrap();
+        if let Some(bar) = opt_bar {
+            canvas
+                .string(2, tex_y as i16 - 9, &bar.to_string(), col)
+                .unwrap();
+        }
+    }
+}
+
+/// Number of textures needed for the waterfall up to the last shown event.
+/// Without shown events, e.g. all shown tracks are hidden, none is needed.
+pub fn waterfall_texture_count(
+    show_events: &[(u64, usize, midi_sequencer::MidiEvent)],
+    net_rows: u32,
+    rows_per_s: u32,
+) -> u32 {
+    let maxtime_us = match show_events.last() {
+        Some((time_us, _, _)) => *time_us,
+        None => return 0,
+    };
+    let rows = (maxtime_us * rows_per_s as u64 + 999_999) / 1_000_000;
+    ((rows + net_rows as u64 - 1) / net_rows as u64) as u32
+}
+
+pub fn draw_waterfall<T: sdl2::render::RenderTarget, C>(
     keyboard: &piano_keyboard::Keyboard2d,
-    canvas: &mut sdl2::render::Canvas<sdl2::video::Window>,
+    canvas: &mut sdl2::render::Canvas<T>,
     i: u32,
     bottom_row: u32,
     net_rows: u32,
     overlap: u32,
     rows_per_s: u32,
     show_events: &Vec<(u64, usize, midi_sequencer::MidiEvent)>,
+    grid: &[(u64, Option<u64>)],
+    labels: &Labels<C>,
 ) {
     // The waterfall is flowing from top to bottom with SDL having origin top left.
     // Thus every texture has to fill from bottom to top.
@@ -169,6 +365,7 @@ pub fn draw_waterfall(
         canvas.set_draw_color(sdl2::pixels::Color::RGB(100, 100, 100));
     }
     canvas.clear();
+    draw_grid(canvas, bottom_row, net_rows, overlap, rows_per_s, grid);
 
     let left_key = keyboard.left_white_key;
     let mut rect_templates: Vec<sdl2::rect::Rect> = vec![];
@@ -192,6 +389,7 @@ pub fn draw_waterfall(
     for (i, _el) in keyboard.iter().enumerate() {
         let sel_key = left_key + i as u8;
         let mut opt_start = None;
+        let mut start_us = 0;
         let mut opt_end = None;
         for (time, trk, evt) in show_events.iter() {
             let col = trk2col(*trk, sel_key);
@@ -200,6 +398,7 @@ pub fn draw_waterfall(
                     if *key == sel_key && *pressure > 0 =>
                 {
                     opt_start = Some((time * rows_per_s as u64 / 1_000_000) as u32);
+                    start_us = *time;
                     trace!("{}: {:?}  {:?}", time, evt, opt_start);
                 }
                 midi_sequencer::MidiEvent::NoteOn(_channel, key, 0)
@@ -243,6 +442,30 @@ pub fn draw_waterfall(
                             col,
                         )
                         .unwrap();
+
+                    // Labels are placed at the start of the whole note,
+                    // which may be in the texture below
+                    let mut label_rec = sdl2::rect::Rect::new(
+                        rec.x(),
+                        top_row as i32 - end as i32,
+                        rec.width(),
+                        (end - start).saturating_sub(rounding.max(0) as u32 / 2 + 1),
+                    );
+                    let text_col = Color::RGB(0, 0, 0);
+                    if let Some(finger) =
+                        labels
+                            .fingering
+                            .transposed_finger(sel_key, labels.shift_key, start_us)
+                    {
+                        if let Some(height) =
+                            labels.draw(canvas, &finger.to_string(), text_col, label_rec)
+                        {
+                            label_rec.set_height(label_rec.height() - height);
+                        }
+                    }
+                    if let Some(note_names) = labels.note_names {
+                        labels.draw(canvas, &note_names.name(sel_key), text_col, label_rec);
+                    }
                     opt_start = None;
                     opt_end = None;
                 }
@@ -350,7 +573,7 @@ pub fn copy_waterfall_to_screen(
         let dst_rect = sdl2::rect::Rect::new(0, y_dst, wf_width, cp_height);
         trace!(target: "copy_texture", "Copy {:?}->{:?}", src_rect, dst_rect);
         let cmd = DrawCommand::CopyToScreen {
-            src_texture: i + 2,
+            src_texture: i + WATERFALL_TEXTURE,
             src_rect,
             dst_rect,
         };
@@ -361,6 +584,7 @@ pub fn copy_waterfall_to_screen(
 #[cfg(test)]
 mod tests {
     use crate::draw_engine;
+    use crate::midi_sequencer::MidiEvent;
 
     #[test]
     fn test_01() {
@@ -387,7 +611,7 @@ mod tests {
                 assert_eq!(src_rect.height() as i32 + src_rect.top(), src_rect.bottom());
 
                 // Last texture is on top. So destination y must be 0 and source y max
-                assert_eq!(src_texture, 4);
+                assert_eq!(src_texture, draw_engine::WATERFALL_TEXTURE + 2);
                 assert_eq!(src_rect.left(), 0);
                 assert_eq!(src_rect.width(), wf_width);
                 assert_eq!(dst_rect.left(), 0);
@@ -406,7 +630,7 @@ mod tests {
                 dst_rect,
             } => {
                 // Middle texture is in the middle
-                assert_eq!(src_texture, 3);
+                assert_eq!(src_texture, draw_engine::WATERFALL_TEXTURE + 1);
                 assert_eq!(src_rect.left(), 0);
                 assert_eq!(src_rect.width(), wf_width);
                 assert_eq!(dst_rect.left(), 0);
@@ -424,7 +648,7 @@ mod tests {
             } => {
                 // First texture is at the bottom. So destination y must be max
                 // and source y equal overlap
-                assert_eq!(src_texture, 2);
+                assert_eq!(src_texture, draw_engine::WATERFALL_TEXTURE);
                 assert_eq!(src_rect.left(), 0);
                 assert_eq!(src_rect.width(), wf_width);
                 assert_eq!(dst_rect.left(), 0);
@@ -437,4 +661,13 @@ mod tests {
         }
         assert_eq!(dst_total_height, wf_height);
     }
+    #[test]
+    fn test_02() {
+        let note_on = MidiEvent::NoteOn(0, 60, 100);
+        assert_eq!(draw_engine::waterfall_texture_count(&[], 907, 100), 0);
+        let events = vec![(9_070_000, 0, note_on)];
+        assert_eq!(draw_engine::waterfall_texture_count(&events, 907, 100), 1);
+        let events = vec![(0, 0, note_on), (9_080_000, 0, note_on)];
+        assert_eq!(draw_engine::waterfall_texture_count(&events, 907, 100), 2);
+    }
 }
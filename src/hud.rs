@@ -0,0 +1,94 @@
+use std::path::Path;
+
+use crate::tempo_map::TempoMap;
+
+/// State of the song shown in the heads-up display
+pub struct Hud<'a> {
+    pub midi_fname: &'a str,
+    pub pos_us: i64,
+    pub duration_us: u64,
+    pub scale_1000: u16,
+    pub shift_key: i8,
+    pub paused: bool,
+}
+impl<'a> Hud<'a> {
+    /// Lines of text to display. Bar and tempo are only available with a
+    /// tempo map of a midi file with metrical timing.
+    pub fn lines(&self, tempo_map: Option<&TempoMap>) -> Vec<String> {
+        let fname = Path::new(self.midi_fname)
+            .file_name()
+            .map_or(self.midi_fname.into(), |name| name.to_string_lossy());
+        let mut lines = vec![
+            fname.to_string(),
+            format!(
+                "{} / {}{}",
+                format_time(self.pos_us),
+                format_time(self.duration_us as i64),
+                if self.paused { " paused" } else { "" }
+            ),
+        ];
+        if let Some(tempo_map) = tempo_map {
+            let tick = tempo_map.us_to_tick(self.pos_us.max(0) as u64);
+            if let Some((bar, beat)) = tempo_map.bar_beat_at(tick) {
+                lines.push(format!("Bar {}:{}", bar, beat));
+            }
+            if let Some(bpm) = tempo_map.bpm_at(tick) {
+                lines.push(format!("{:.0} BPM", bpm));
+            }
+        }
+        lines.push(format!("Speed {}%", self.scale_1000 / 10));
+        lines.push(format!("Transpose {:+}", self.shift_key));
+        lines
+    }
+}
+
+/// Time as minutes and seconds like 1:02 or -0:03 before the start
+pub fn format_time(us: i64) -> String {
+    let sign = if us < 0 { "-" } else { "" };
+    let seconds = us.abs() / 1_000_000;
+    format!("{}{}:{:02}", sign, seconds / 60, seconds % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hud::{format_time, Hud};
+    use crate::midi_container::MidiContainer;
+    use crate::test_fixtures::{single_track, END_OF_TRACK};
+
+    #[test]
+    fn test_01() {
+        assert_eq!(format_time(0), "0:00");
+        assert_eq!(format_time(62_500_000), "1:02");
+        assert_eq!(format_time(-3_000_000), "-0:03");
+        assert_eq!(format_time(3_600_000_000), "60:00");
+    }
+
+    #[test]
+    fn test_02() {
+        // 384 ticks per quarter note at the default tempo of 120 bpm
+        let buf = single_track(END_OF_TRACK.to_vec());
+        let smf_buf = midly::Smf::parse(&buf).unwrap();
+        let container = MidiContainer::from_buf(&smf_buf).unwrap();
+        let tempo_map = container.tempo_map();
+        let hud = Hud {
+            midi_fname: "songs/Marche_aux_Flambeaux.mid",
+            pos_us: 2_750_000,
+            duration_us: 125_000_000,
+            scale_1000: 800,
+            shift_key: -2,
+            paused: true,
+        };
+        assert_eq!(
+            hud.lines(Some(&tempo_map)),
+            vec![
+                "Marche_aux_Flambeaux.mid",
+                "0:02 / 2:05 paused",
+                "Bar 2:2",
+                "120 BPM",
+                "Speed 80%",
+                "Transpose -2",
+            ]
+        );
+        assert_eq!(hud.lines(None).len(), 4);
+    }
+}
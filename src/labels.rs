@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use crate::midi_container::{note_name, MidiContainer};
+use crate::tempo_map::{SongPosition, TempoMap};
+
+/// A finger number belongs to a note starting within this time
+const FINGER_TOLERANCE_US: u64 = 50_000;
+
+/// Naming of the notes shown in the waterfall and on the keys
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoteNames {
+    English,
+    Solfege,
+}
+impl NoteNames {
+    pub fn name(self, key: u8) -> String {
+        const SOLFEGE: [&str; 12] = [
+            "Do", "Do#", "Re", "Re#", "Mi", "Fa", "Fa#", "Sol", "Sol#", "La", "La#", "Si",
+        ];
+        match self {
+            NoteNames::English => note_name(key),
+            NoteNames::Solfege => format!("{}{}", SOLFEGE[key as usize % 12], key as i16 / 12 - 1),
+        }
+    }
+}
+impl FromStr for NoteNames {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "english" => Ok(NoteNames::English),
+            "solfege" => Ok(NoteNames::Solfege),
+            _ => Err(format!("Invalid note names '{}'", s)),
+        }
+    }
+}
+
+/// Parses a midi key given as number or as name with octave like C4, F#3 or Bb2
+pub fn parse_note(s: &str) -> Option<u8> {
+    if let Ok(key) = s.parse::<u8>() {
+        return if key < 128 { Some(key) } else { None };
+    }
+    let mut chars = s.chars();
+    let base: i16 = match chars.next()?.to_ascii_uppercase() {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let (shift, octave) = if let Some(octave) = rest.strip_prefix('#') {
+        (1, octave)
+    } else if let Some(octave) = rest.strip_prefix('b') {
+        (-1, octave)
+    } else {
+        (0, rest)
+    };
+    let octave = octave.parse::<i16>().ok()?;
+    let key = (octave + 1) * 12 + base + shift;
+    if (0..128).contains(&key) {
+        Some(key as u8)
+    } else {
+        None
+    }
+}
+
+/// Finger numbers of the notes by key and start time
+#[derive(Clone, Debug, Default)]
+pub struct Fingering {
+    fingers: BTreeMap<(u8, u64), u8>,
+}
+impl Fingering {
+    pub fn new() -> Fingering {
+        Fingering::default()
+    }
+    pub fn add(&mut self, key: u8, time_us: u64, finger: u8) {
+        self.fingers.insert((key, time_us), finger);
+    }
+    pub fn is_empty(&self) -> bool {
+        self.fingers.is_empty()
+    }
+    /// Finger of the note starting nearest to time_us
+    pub fn finger(&self, key: u8, time_us: u64) -> Option<u8> {
+        let from = (key, time_us.saturating_sub(FINGER_TOLERANCE_US));
+        let to = (key, time_us + FINGER_TOLERANCE_US);
+        self.fingers
+            .range(from..=to)
+            .min_by_key(|((_, t), _)| (*t as i64 - time_us as i64).abs())
+            .map(|(_, finger)| *finger)
+    }
+    /// Finger of a note shown transposed by shift_key, whose fingering is
+    /// given for the original key
+    pub fn transposed_finger(&self, key: u8, shift_key: i8, time_us: u64) -> Option<u8> {
+        let key = key as i16 - shift_key as i16;
+        if (0..128).contains(&key) {
+            self.finger(key as u8, time_us)
+        } else {
+            None
+        }
+    }
+    /// Reads a sidecar file with lines of `<position> <note> <finger>`.
+    /// The position is given like for loops and empty lines or lines
+    /// starting with '#' are skipped.
+    pub fn from_text(text: &str, tempo_map: &TempoMap) -> Result<Fingering, String> {
+        let mut fingering = Fingering::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let err = || format!("Invalid fingering in line {}: {}", i + 1, line);
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            if fields.len() != 3 {
+                return Err(err());
+            }
+            let time_us = fields[0]
+                .parse::<SongPosition>()
+                .ok()
+                .and_then(|pos| pos.to_us(tempo_map))
+                .ok_or_else(err)?;
+            let key = parse_note(fields[1]).ok_or_else(err)?;
+            let finger = parse_fingers(fields[2])
+                .and_then(|fingers| fingers.first().cloned())
+                .ok_or_else(err)?;
+            fingering.add(key, time_us, finger);
+        }
+        Ok(fingering)
+    }
+    /// Text or lyric events like "1" or "1 3 5" give the fingers of the
+    /// notes starting at the same time in the same track from low to high
+    pub fn from_midi<'m>(container: &'m MidiContainer<'m>) -> Fingering {
+        let mut texts = BTreeMap::new();
+        let mut chords: BTreeMap<(usize, u64), Vec<u8>> = BTreeMap::new();
+        for (time_us, trk, evt) in container.iter().timed(&container.header().timing) {
+            match evt {
+                midly::EventKind::Meta(midly::MetaMessage::Text(raw))
+                | midly::EventKind::Meta(midly::MetaMessage::Lyric(raw)) => {
+                    if let Some(fingers) = parse_fingers(&String::from_utf8_lossy(raw)) {
+                        texts.insert((trk, time_us), fingers);
+                    }
+                }
+                midly::EventKind::Midi {
+                    message: midly::MidiMessage::NoteOn { key, vel },
+                    ..
+                } if vel.as_int() > 0 => {
+                    chords.entry((trk, time_us)).or_default().push(key.as_int());
+                }
+                _ => (),
+            }
+        }
+        let mut fingering = Fingering::new();
+        for ((trk, time_us), fingers) in texts.into_iter() {
+            if let Some(keys) = chords.get_mut(&(trk, time_us)) {
+                keys.sort();
+                for (key, finger) in keys.iter().zip(fingers) {
+                    fingering.add(*key, time_us, finger);
+                }
+            }
+        }
+        fingering
+    }
+}
+
+/// Finger numbers 1 to 5 separated by spaces or commas
+fn parse_fingers(s: &str) -> Option<Vec<u8>> {
+    let fingers = s
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|field| !field.is_empty())
+        .map(|field| match field.parse::<u8>() {
+            Ok(finger) if (1..=5).contains(&finger) => Some(finger),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    if fingers.is_empty() {
+        None
+    } else {
+        Some(fingers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::labels::{parse_fingers, parse_note, Fingering, NoteNames};
+    use crate::midi_container::MidiContainer;
+    use crate::test_fixtures::{single_track, END_OF_TRACK};
+
+    #[test]
+    fn test_01() {
+        assert_eq!(NoteNames::English.name(61), "C#4");
+        assert_eq!(NoteNames::Solfege.name(67), "Sol4");
+        assert_eq!(NoteNames::Solfege.name(21), "La0");
+        assert_eq!(parse_note("C4"), Some(60));
+        assert_eq!(parse_note("f#3"), Some(54));
+        assert_eq!(parse_note("Bb2"), Some(46));
+        assert_eq!(parse_note("72"), Some(72));
+        assert_eq!(parse_note("H2"), None);
+        assert_eq!(parse_note("G9"), Some(127));
+        assert_eq!(parse_note("A9"), None);
+        assert_eq!(parse_fingers("1 3,5"), Some(vec![1, 3, 5]));
+        assert_eq!(parse_fingers("6"), None);
+        assert_eq!(parse_fingers("Verse"), None);
+    }
+
+    #[test]
+    fn test_02() {
+        let buf = single_track(END_OF_TRACK.to_vec());
+        let smf_buf = midly::Smf::parse(&buf).unwrap();
+        let container = MidiContainer::from_buf(&smf_buf).unwrap();
+        let tempo_map = container.tempo_map();
+        let text = "# time note finger\n0 C4 1\n\n1.5 64 3\n";
+        let fingering = Fingering::from_text(text, &tempo_map).unwrap();
+        assert_eq!(fingering.finger(60, 0), Some(1));
+        assert_eq!(fingering.finger(60, 40_000), Some(1));
+        assert_eq!(fingering.finger(60, 60_000), None);
+        assert_eq!(fingering.finger(64, 1_480_000), Some(3));
+        assert_eq!(fingering.finger(62, 1_500_000), None);
+        // Transposed up by two semitones C4 is shown as D4
+        assert_eq!(fingering.transposed_finger(62, 2, 0), Some(1));
+        assert_eq!(fingering.transposed_finger(60, 2, 0), None);
+        assert_eq!(fingering.transposed_finger(63, -1, 1_500_000), Some(3));
+        assert_eq!(fingering.transposed_finger(0, 1, 0), None);
+        assert!(Fingering::from_text("0 C4", &tempo_map).is_err());
+        assert!(Fingering::from_text("0 C4 7", &tempo_map).is_err());
+    }
+
+    #[test]
+    fn test_03() {
+        // Lyric "1 3" for a chord of E4 and C4, which are fingered from low to high
+        let track = vec![
+            0x00, 0xff, 0x05, 0x03, b'1', b' ', b'3', // lyric
+            0x00, 0x90, 0x40, 0x40, // note on E4
+            0x00, 0x90, 0x3c, 0x40, // note on C4
+            0x83, 0x00, 0xff, 0x01, 0x05, b'V', b'e', b'r', b's', b'e', // text
+            0x00, 0x90, 0x3e, 0x40, // note on D4
+            0x00, 0xff, 0x2f, 0x00, // end of track
+        ];
+        let buf = single_track(track);
+        let smf_buf = midly::Smf::parse(&buf).unwrap();
+        let container = MidiContainer::from_buf(&smf_buf).unwrap();
+        let fingering = Fingering::from_midi(&container);
+        assert_eq!(fingering.finger(60, 0), Some(1));
+        assert_eq!(fingering.finger(64, 0), Some(3));
+        assert_eq!(fingering.finger(62, 500_000), None);
+    }
+}
@@ -3,16 +3,32 @@ use std::time::Duration;
 
 use log::*;
 
+use sdl2::gfx::primitives::DrawRenderer;
 use sdl2_timing::Sdl2Timing;
 
 //mod app;
 mod app_control;
+mod chase;
 mod draw_engine;
+mod hud;
+mod labels;
+mod metronome;
 mod midi_container;
+mod midi_input;
+mod midi_ports;
 mod midi_sequencer;
+mod mixer;
+mod part;
+mod practice;
+mod recorder;
+mod renderer;
+mod scoring;
 mod scroller;
 mod sdl_event_processor;
 mod stderrlog;
+mod tempo_map;
+#[cfg(test)]
+mod test_fixtures;
 mod time_controller;
 mod usage; // Hacked version of stderrlog crate
 
@@ -50,8 +66,18 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         _ => LevelFilter::Trace,
     });
 
+    if control.list_ports_command() {
+        return midi_ports::list_command();
+    }
     if control.list_command() {
-        return midi_container::list_command(control.is_quiet(), &control.midi_fname());
+        return midi_container::list_command(
+            control.is_quiet(),
+            control.list_json(),
+            &control.midi_fname(),
+        );
+    }
+    if let Some(settings) = control.take_render_settings() {
+        return renderer::render(&mut control, &settings);
     }
 
     let only_midi_player = control.show_tracks().len() == 0;
@@ -65,17 +91,26 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             control.shift_key(),
             control.show_tracks().clone(),
             control.play_tracks().clone(),
+            false,
         )?;
+        // Only recording needs the midi input without shown tracks
+        control.connect_midi_input()?;
         control.play_midi_data(play_events);
         loop {
-            sleep(Duration::from_millis(1000));
+            sleep(Duration::from_millis(100));
+            control.process_midi_input();
+            control.check_loop_passes();
             if control.seq_is_finished() {
                 break;
             }
         }
-        return Ok(());
+        control.close_sequencer();
+        sleep(Duration::from_millis(150));
+        return control.finish_recording();
     }
 
+    control.connect_midi_input()?;
+
     let nr_of_keys = control.right_key() - control.left_key() + 1;
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
@@ -138,6 +173,20 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .build()?;
     let texture_creator = canvas.texture_creator();
     let mut textures: Vec<sdl2::render::Texture> = vec![];
+    let ttf_context = sdl2::ttf::init()?;
+    let font_path = control.font_path();
+    if font_path.is_none() {
+        warn!("No font found for heads-up display, note names and finger numbers");
+    }
+    let need_labels = control.note_names().is_some() || !control.fingering().is_empty();
+    let hud_font = match font_path {
+        Some(ref path) => ttf_context
+            .load_font(path, 14)
+            .map_err(|e| warn!("Cannot load font {:?}: {}", path, e))
+            .ok(),
+        None => None,
+    };
+    let mut font = None;
 
     let mut event_pump = sdl_context.event_pump().unwrap();
 
@@ -183,8 +232,21 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         if textures.len() == 0 {
             trace!("Create keyboard textures");
             if let Some(keyboard) = control.get_keyboard() {
-                // Texture 0 are for unpressed and 1 for pressed keys
-                for pressed in vec![false, true].drain(..) {
+                if let (true, Some(path)) = (need_labels, font_path.as_ref()) {
+                    font = ttf_context
+                        .load_font(path, draw_engine::label_font_size(keyboard))
+                        .map_err(|e| warn!("Cannot load font {:?}: {}", path, e))
+                        .ok();
+                }
+                let labels = draw_engine::Labels {
+                    font: font.as_ref(),
+                    texture_creator: &texture_creator,
+                    note_names: control.note_names(),
+                    fingering: control.fingering(),
+                    shift_key: control.shift_key(),
+                };
+                // Texture 0 are for unpressed and 1.. for pressed keys
+                for key_texture in draw_engine::KEY_TEXTURES.iter() {
                     let mut texture = texture_creator
                         .create_texture_target(
                             texture_creator.default_pixel_format(),
@@ -193,7 +255,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         )
                         .unwrap();
                     canvas.with_texture_canvas(&mut texture, |tex_canvas| {
-                        draw_engine::draw_keyboard(keyboard, tex_canvas, pressed).ok();
+                        draw_engine::draw_keyboard(keyboard, tex_canvas, *key_texture, &labels)
+                            .ok();
                     })?;
                     textures.push(texture);
                 }
@@ -213,19 +276,24 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 width,
                 keyboard.height as u32,
             );
-            canvas.copy(&textures[0], None, dst_rec)?;
+            canvas.copy(
+                &textures[draw_engine::KeyTexture::Unpressed as usize],
+                None,
+                dst_rec,
+            )?;
             st.sample("copy keyboard to canvas");
         }
 
         if control.show_events().is_some() {
-            if textures.len() <= 2 {
-                // Texture 2.. are for waterfall.
+            if textures.len() <= draw_engine::WATERFALL_TEXTURE {
+                // Texture WATERFALL_TEXTURE.. are for waterfall.
                 //
-                let maxtime_us = control.show_events().unwrap()[control.show_events_len() - 1].0;
-                let rows = (maxtime_us * rows_per_s as u64 + 999_999) / 1_000_000;
-                let nr_of_textures =
-                    ((rows + waterfall_net_height as u64 - 1) / waterfall_net_height as u64) as u32;
-                trace!("Needed rows/textures: {}/{}", rows, nr_of_textures);
+                let nr_of_textures = draw_engine::waterfall_texture_count(
+                    control.show_events().unwrap(),
+                    waterfall_net_height,
+                    rows_per_s,
+                );
+                trace!("Needed textures: {}", nr_of_textures);
                 for i in 0..nr_of_textures {
                     let mut texture = texture_creator
                         .create_texture_target(
@@ -235,6 +303,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         )
                         .unwrap();
                     if let Some(keyboard) = control.get_keyboard() {
+                        let labels = draw_engine::Labels {
+                            font: font.as_ref(),
+                            texture_creator: &texture_creator,
+                            note_names: control.note_names(),
+                            fingering: control.fingering(),
+                            shift_key: control.shift_key(),
+                        };
                         canvas.with_texture_canvas(&mut texture, |tex_canvas| {
                             draw_engine::draw_waterfall(
                                 keyboard,
@@ -245,6 +320,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                                 waterfall_overlap,
                                 rows_per_s,
                                 &control.show_events().unwrap(),
+                                control.beat_grid(),
+                                &labels,
                             );
                         })?;
                     }
@@ -265,9 +342,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     rec.height() - keyboard.height as u32 - 1,
                     pos_us,
                     &control.show_events().unwrap(),
+                    control.input_pressed(),
                 );
                 let mut draw_commands_2 = draw_engine::copy_waterfall_to_screen(
-                    textures.len() - 2,
+                    textures.len() - draw_engine::WATERFALL_TEXTURE,
                     rec.width(),
                     rec.height() - keyboard.height as u32,
                     waterfall_net_height,
@@ -297,10 +375,41 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
         st.sample("waterfall and pressed keys drawn");
 
+        // The heads-up display includes the overlay texts
+        let pos_us = control.get_pos_us_after(st.us_till_next_frame());
+        if let Some(lines) = control.hud_lines(pos_us) {
+            draw_engine::draw_hud(&mut canvas, &lines, hud_font.as_ref(), &texture_creator)?;
+        } else {
+            let overlay = [
+                control.score_text(),
+                control.speed_text(),
+                control.mixer_text(),
+            ];
+            for (i, text) in overlay.iter().flatten().enumerate() {
+                let y = 10 + 12 * i as i16;
+                canvas.string(10, y, text, sdl2::pixels::Color::RGB(255, 255, 255))?;
+            }
+        }
+
+        if let Some(beats) = control.count_in_beats() {
+            // Enlarge the 8x8 pixel font for the countdown
+            let scale = 8;
+            let text = format!("{}", beats);
+            let x = (width / scale / 2) as i16 - 4 * text.len() as i16;
+            let y = (rec.height() / scale / 3) as i16;
+            canvas.set_scale(scale as f32, scale as f32)?;
+            canvas.string(x, y, &text, sdl2::pixels::Color::RGB(255, 255, 255))?;
+            canvas.set_scale(1.0, 1.0)?;
+        }
+
         control.update_position_if_scrolling();
     }
+    // Do not leave notes hanging on exit
+    control.close_sequencer();
     sleep(Duration::from_millis(150));
 
+    control.finish_scoring()?;
+    control.finish_recording()?;
     st.output();
     Ok(())
 }
@@ -0,0 +1,149 @@
+use crate::midi_sequencer::{MidiEvent, RawMidiTuple};
+use crate::tempo_map::TempoMap;
+
+/// Percussion channel 10 of general midi
+const PERCUSSION_CHANNEL: u8 = 9;
+const CLICK_DURATION_US: u64 = 50_000;
+const DOWNBEAT_VELOCITY: u8 = 110;
+const BEAT_VELOCITY: u8 = 80;
+
+/// Clicks are sent with this track index, which is not used by a midi file
+pub const METRONOME_TRACK: usize = usize::MAX;
+
+fn push_click(
+    clicks: &mut Vec<RawMidiTuple>,
+    time_us: u64,
+    is_downbeat: bool,
+    downbeat_key: u8,
+    beat_key: u8,
+) {
+    let (key, vel) = if is_downbeat {
+        (downbeat_key, DOWNBEAT_VELOCITY)
+    } else {
+        (beat_key, BEAT_VELOCITY)
+    };
+    clicks.push((
+        time_us,
+        METRONOME_TRACK,
+        MidiEvent::NoteOn(PERCUSSION_CHANNEL, key, vel),
+    ));
+    clicks.push((
+        time_us + CLICK_DURATION_US,
+        METRONOME_TRACK,
+        MidiEvent::NoteOff(PERCUSSION_CHANNEL, key, 0),
+    ));
+}
+
+/// Metronome clicks on every beat up to end_tick derived from the tempo and
+/// time signature changes of the midi file.
+pub fn clicks(
+    tempo_map: &TempoMap,
+    end_tick: u64,
+    downbeat_key: u8,
+    beat_key: u8,
+) -> Vec<RawMidiTuple> {
+    let mut clicks = vec![];
+    for (tick, is_downbeat) in tempo_map.beats(end_tick).into_iter() {
+        let time_us = tempo_map.tick_to_us(tick);
+        push_click(&mut clicks, time_us, is_downbeat, downbeat_key, beat_key);
+    }
+    clicks.sort_by_key(|click| click.0);
+    clicks
+}
+
+/// Count-in of some bars before playing starts
+#[derive(Clone)]
+pub struct CountIn {
+    pub bars: u64,
+    pub tempo_map: TempoMap,
+    pub downbeat_key: u8,
+    pub beat_key: u8,
+}
+impl CountIn {
+    /// Returns the clicks for counting in before resuming at pos_us and the
+    /// duration of the count-in. The time of the clicks is real time in us
+    /// from the start of the count-in, so the speed scaling is applied.
+    pub fn clicks(&self, pos_us: i64, scale_1000: u16) -> Option<(Vec<RawMidiTuple>, u64)> {
+        let tick = self.tempo_map.us_to_tick(pos_us.max(0) as u64);
+        let (beats_per_bar, us_per_beat) = self.tempo_map.beat_at(tick)?;
+        let us_per_beat = us_per_beat * 1000 / scale_1000.max(1) as u64;
+        let mut clicks = vec![];
+        for beat in 0..self.bars * beats_per_bar {
+            let is_downbeat = beat % beats_per_bar == 0;
+            let time_us = beat * us_per_beat;
+            push_click(
+                &mut clicks,
+                time_us,
+                is_downbeat,
+                self.downbeat_key,
+                self.beat_key,
+            );
+        }
+        clicks.sort_by_key(|click| click.0);
+        Some((clicks, self.bars * beats_per_bar * us_per_beat))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metronome;
+    use crate::metronome::CountIn;
+    use crate::midi_container::MidiContainer;
+    use crate::midi_sequencer::MidiEvent;
+
+    #[test]
+    fn test_01() {
+        let midi_fname = "Marche_aux_Flambeaux.mid";
+        let buf = std::fs::read(midi_fname).unwrap();
+        let smf_buf = midly::Smf::parse(&buf).unwrap();
+        let container = MidiContainer::from_buf(&smf_buf).unwrap();
+        let tempo_map = container.tempo_map();
+        let clicks = metronome::clicks(&tempo_map, 5 * 384, 76, 77);
+        assert_eq!(clicks.len(), 10);
+        let notes = clicks
+            .iter()
+            .filter_map(|(time_us, _, evt)| match evt {
+                MidiEvent::NoteOn(9, key, _) => Some((*time_us, *key)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            notes,
+            vec![
+                (0, 76),
+                (545_454, 77),
+                (1_090_908, 77),
+                (1_636_362, 77),
+                (2_181_816, 76)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_02() {
+        let midi_fname = "Marche_aux_Flambeaux.mid";
+        let buf = std::fs::read(midi_fname).unwrap();
+        let smf_buf = midly::Smf::parse(&buf).unwrap();
+        let container = MidiContainer::from_buf(&smf_buf).unwrap();
+        let count_in = CountIn {
+            bars: 2,
+            tempo_map: container.tempo_map(),
+            downbeat_key: 76,
+            beat_key: 77,
+        };
+        // At half speed a beat lasts twice as long
+        let (clicks, duration_us) = count_in.clicks(10_000_000, 500).unwrap();
+        assert_eq!(duration_us, 8 * 1_090_908);
+        let notes = clicks
+            .iter()
+            .filter_map(|(time_us, _, evt)| match evt {
+                MidiEvent::NoteOn(9, key, _) => Some((*time_us, *key)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(notes.len(), 8);
+        assert_eq!(notes[0], (0, 76));
+        assert_eq!(notes[1], (1_090_908, 77));
+        assert_eq!(notes[4], (4 * 1_090_908, 76));
+    }
+}
@@ -3,6 +3,9 @@ use std::io::{Error, ErrorKind};
 use std::iter::Iterator;
 
 use log::*;
+use serde_json::json;
+
+use crate::tempo_map::TempoMap;
 
 pub struct TrackState<'m> {
     trk_number: usize,
@@ -98,25 +101,7 @@ impl<'m> Iterator for MidiIterator<'m> {
 
 pub struct MidiTimedIterator<'m> {
     opt_midi_iter: Option<MidiIterator<'m>>,
-    timing: &'m midly::Timing,
-    timebase: Option<u64>,
-    current_time_us: u64,
-    last_tick: u32,
-}
-impl<'m> MidiTimedIterator<'m> {
-    fn update_timebase(&mut self, tempo: u32) {
-        let ppqn = match self.timing {
-            // http://www.onicos.com/staff/iz/formats/smf006.html
-            // http://midiwonder.com/midifile.html
-            //
-            // tempo = 24ths of a microsecond per MIDI clock
-            midly::Timing::Metrical(x) => x.as_int() as u32,
-            midly::Timing::Timecode(_x, _y) => panic!("Timecode not implemented"),
-        };
-        let bpm = 60_000_000 / tempo as u64;
-
-        self.timebase = Some(60_000_000 / ppqn as u64 / bpm);
-    }
+    tempo_map: TempoMap,
 }
 impl<'m> Iterator for MidiTimedIterator<'m> {
     type Item = (u64, usize, &'m midly::EventKind<'m>);
@@ -127,17 +112,14 @@ impl<'m> Iterator for MidiTimedIterator<'m> {
             }
             let opt_tuple = self.opt_midi_iter.as_mut().unwrap().next();
             if let Some((time, trk, evt_kind)) = opt_tuple {
-                let dt = time - self.last_tick as u64;
-                if dt > 0 {
-                    self.last_tick = time as u32;
-                    self.current_time_us += dt as u64 * self.timebase.unwrap();
-                }
+                // Events of all tracks are merged in order of their ticks,
+                // so tempo changes in any track apply from their position on.
                 match evt_kind {
                     &midly::EventKind::Meta(midly::MetaMessage::Tempo(tmp)) => {
-                        self.update_timebase(tmp.as_int());
+                        self.tempo_map.add_tempo(time, tmp.as_int());
                     }
                     _ => {
-                        return Some((self.current_time_us, trk, evt_kind));
+                        return Some((self.tempo_map.tick_to_us(time), trk, evt_kind));
                     }
                 }
             } else {
@@ -151,10 +133,7 @@ impl<'m> MidiIterator<'m> {
     pub fn timed(self, timing: &'m midly::Timing) -> MidiTimedIterator<'m> {
         MidiTimedIterator {
             opt_midi_iter: Some(self),
-            timing,
-            timebase: None,
-            current_time_us: 0,
-            last_tick: 0,
+            tempo_map: TempoMap::new(timing),
         }
     }
 }
@@ -179,13 +158,174 @@ impl<'m> MidiContainer<'m> {
     pub fn nr_of_tracks(&'m self) -> usize {
         self.smf.tracks.len()
     }
+    pub fn tempo_map(&'m self) -> TempoMap {
+        let mut tempo_map = TempoMap::new(&self.header().timing);
+        for (time, _, evt_kind) in self.iter() {
+            match evt_kind {
+                midly::EventKind::Meta(midly::MetaMessage::Tempo(tmp)) => {
+                    tempo_map.add_tempo(time, tmp.as_int());
+                }
+                midly::EventKind::Meta(midly::MetaMessage::TimeSignature(num, den_pow, _, _)) => {
+                    tempo_map.add_time_signature(time, *num, *den_pow);
+                }
+                _ => (),
+            }
+        }
+        tempo_map
+    }
+    /// Track and instrument names of every track
+    pub fn track_names(&'m self) -> Vec<Vec<String>> {
+        let mut names = vec![vec![]; self.nr_of_tracks()];
+        for (_, trk, evt_kind) in self.iter() {
+            match evt_kind {
+                midly::EventKind::Meta(midly::MetaMessage::TrackName(raw))
+                | midly::EventKind::Meta(midly::MetaMessage::InstrumentName(raw)) => {
+                    let name = String::from_utf8_lossy(raw).trim().to_string();
+                    if !name.is_empty() && !names[trk].contains(&name) {
+                        names[trk].push(name);
+                    }
+                }
+                _ => (),
+            }
+        }
+        names
+    }
+}
+
+/// Name of the midi key with octave, e.g. 60 is C4
+pub fn note_name(key: u8) -> String {
+    const NAMES: [&str; 12] = [
+        "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+    ];
+    format!("{}{}", NAMES[key as usize % 12], key as i16 / 12 - 1)
+}
+
+#[derive(Clone, Default)]
+struct NoteStats {
+    count: usize,
+    range: Option<(u8, u8)>,
+}
+impl NoteStats {
+    fn add(&mut self, key: u8) {
+        self.count += 1;
+        self.range = Some(match self.range {
+            Some((lowest, highest)) => (lowest.min(key), highest.max(key)),
+            None => (key, key),
+        });
+    }
 }
 
-pub fn list_command(quiet: bool, midi_fname: &str) -> Result<(), Box<dyn std::error::Error>> {
+fn key_json(key: u8) -> serde_json::Value {
+    json!({ "key": key, "name": note_name(key) })
+}
+
+/// Statistics of the song and its tracks for indexing song libraries
+pub fn song_info<'m>(container: &'m MidiContainer<'m>) -> serde_json::Value {
+    let tempo_map = container.tempo_map();
+    let nr_of_tracks = container.nr_of_tracks();
+    let mut names = vec![None; nr_of_tracks];
+    let mut instruments = vec![None; nr_of_tracks];
+    let mut used_channels = vec![vec![false; 16]; nr_of_tracks];
+    let mut notes = vec![NoteStats::default(); nr_of_tracks];
+    let mut programs = vec![vec![]; nr_of_tracks];
+    let mut durations_us = vec![0; nr_of_tracks];
+    let mut tempos = vec![];
+    let mut time_signatures = vec![];
+    for (tick, trk, evt_kind) in container.iter() {
+        let time_us = tempo_map.tick_to_us(tick);
+        match evt_kind {
+            midly::EventKind::Midi { channel, message } => {
+                used_channels[trk][channel.as_int() as usize] = true;
+                match message {
+                    midly::MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => {
+                        notes[trk].add(key.as_int());
+                    }
+                    // The track lasts until its last note ends
+                    midly::MidiMessage::NoteOn { .. } | midly::MidiMessage::NoteOff { .. } => {
+                        durations_us[trk] = time_us;
+                    }
+                    midly::MidiMessage::ProgramChange { program } => {
+                        programs[trk].push(json!({
+                            "time_us": time_us,
+                            "channel": channel.as_int(),
+                            "program": program.as_int(),
+                        }));
+                    }
+                    _ => (),
+                }
+            }
+            midly::EventKind::Meta(midly::MetaMessage::TrackName(raw)) => {
+                names[trk] = names[trk]
+                    .take()
+                    .or_else(|| Some(String::from_utf8_lossy(raw).to_string()));
+            }
+            midly::EventKind::Meta(midly::MetaMessage::InstrumentName(raw)) => {
+                instruments[trk] = instruments[trk]
+                    .take()
+                    .or_else(|| Some(String::from_utf8_lossy(raw).to_string()));
+            }
+            midly::EventKind::Meta(midly::MetaMessage::Tempo(us_per_beat)) => {
+                tempos.push(json!({
+                    "time_us": time_us,
+                    "us_per_quarter": us_per_beat.as_int(),
+                    "bpm": 60_000_000.0 / us_per_beat.as_int() as f64,
+                }));
+            }
+            midly::EventKind::Meta(midly::MetaMessage::TimeSignature(num, den_pow, _, _)) => {
+                time_signatures.push(json!({
+                    "time_us": time_us,
+                    "numerator": num,
+                    "denominator": 1u32 << den_pow,
+                }));
+            }
+            _ => (),
+        }
+    }
+    let tracks = (0..nr_of_tracks)
+        .map(|trk| {
+            let channels = (0..16)
+                .filter(|c| used_channels[trk][*c])
+                .collect::<Vec<_>>();
+            json!({
+                "index": trk,
+                "name": names[trk],
+                "instrument": instruments[trk],
+                "channels": channels,
+                "note_count": notes[trk].count,
+                "lowest_note": notes[trk].range.map(|(lowest, _)| key_json(lowest)),
+                "highest_note": notes[trk].range.map(|(_, highest)| key_json(highest)),
+                "duration_us": durations_us[trk],
+                "program_changes": programs[trk],
+            })
+        })
+        .collect::<Vec<_>>();
+    let format = match container.header().format {
+        midly::Format::SingleTrack => 0,
+        midly::Format::Parallel => 1,
+        midly::Format::Sequential => 2,
+    };
+    json!({
+        "format": format,
+        "duration_us": durations_us.iter().max().unwrap_or(&0),
+        "tempo_changes": tempos,
+        "time_signature_changes": time_signatures,
+        "tracks": tracks,
+    })
+}
+
+pub fn list_command(
+    quiet: bool,
+    json_format: bool,
+    midi_fname: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
     let buf = std::fs::read(midi_fname)?;
     let smf_buf =
         midly::Smf::parse(&buf).map_err(|e| Error::new(ErrorKind::Other, format!("{:?}", e)))?;
     let container = MidiContainer::from_buf(&smf_buf)?;
+    if json_format {
+        println!("{}", serde_json::to_string_pretty(&song_info(&container))?);
+        return Ok(());
+    }
     if !quiet {
         for _evt in container.iter() {
             //trace!("{:?}", evt);
@@ -197,13 +337,19 @@ pub fn list_command(quiet: bool, midi_fname: &str) -> Result<(), Box<dyn std::er
     for i in 0..container.nr_of_tracks() {
         println!("Track {}:", i);
         let mut used_channels = vec![false; 16];
+        let mut notes = vec![NoteStats::default(); 16];
         for evt in container.iter().filter(|e| e.1 == i) {
             match evt.2 {
                 midly::EventKind::Midi {
                     channel: c,
-                    message: _m,
+                    message: m,
                 } => {
                     used_channels[c.as_int() as usize] = true;
+                    if let midly::MidiMessage::NoteOn { key, vel } = m {
+                        if vel.as_int() > 0 {
+                            notes[c.as_int() as usize].add(key.as_int());
+                        }
+                    }
                 }
                 midly::EventKind::SysEx(_) => (),
                 midly::EventKind::Escape(_) => (),
@@ -243,6 +389,17 @@ pub fn list_command(quiet: bool, midi_fname: &str) -> Result<(), Box<dyn std::er
                 .map(|(c, _)| c)
                 .collect::<Vec<_>>()
         );
+        for (c, stats) in notes.iter().enumerate() {
+            if let Some((lowest, highest)) = stats.range {
+                println!(
+                    "  Channel {}: {} notes from {} to {}",
+                    c,
+                    stats.count,
+                    note_name(lowest),
+                    note_name(highest)
+                );
+            }
+        }
     }
     Ok(())
 }
@@ -250,6 +407,18 @@ pub fn list_command(quiet: bool, midi_fname: &str) -> Result<(), Box<dyn std::er
 #[cfg(test)]
 mod tests {
     use crate::midi_container;
+    use crate::test_fixtures;
+
+    fn timecode_times(fps: u8, subframes: u8) -> Vec<u64> {
+        let buf = test_fixtures::timecode_smf(fps, subframes);
+        let smf_buf = midly::Smf::parse(&buf).unwrap();
+        let container = midi_container::MidiContainer::from_buf(&smf_buf).unwrap();
+        container
+            .iter()
+            .timed(&container.header().timing)
+            .map(|(time_us, _, _)| time_us)
+            .collect::<Vec<_>>()
+    }
 
     #[test]
     fn test_01() {
@@ -359,6 +528,82 @@ mod tests {
             last_time_us = time_us;
         }
 
-        assert_eq!(last_time_us, 248_102_400);
+        // 174720 ticks at 545454 us per 384 ticks
+        assert_eq!(last_time_us, 248_181_570);
+    }
+
+    #[test]
+    fn test_21() {
+        // 24 fps * 40 subframes = 960 ticks/s
+        assert_eq!(timecode_times(24, 40), vec![0, 1_041_666, 1_041_666]);
+    }
+    #[test]
+    fn test_22() {
+        // 25 fps * 40 subframes = 1000 ticks/s
+        assert_eq!(timecode_times(25, 40), vec![0, 1_000_000, 1_000_000]);
+    }
+    #[test]
+    fn test_23() {
+        // 29.97 fps * 40 subframes = 1198.8 ticks/s
+        assert_eq!(timecode_times(29, 40), vec![0, 834_166, 834_166]);
+    }
+    #[test]
+    fn test_24() {
+        // 30 fps * 40 subframes = 1200 ticks/s
+        assert_eq!(timecode_times(30, 40), vec![0, 833_333, 833_333]);
+    }
+    #[test]
+    fn test_31() {
+        assert_eq!(midi_container::note_name(60), "C4");
+        assert_eq!(midi_container::note_name(21), "A0");
+        assert_eq!(midi_container::note_name(108), "C8");
+        assert_eq!(midi_container::note_name(61), "C#4");
+        assert_eq!(midi_container::note_name(0), "C-1");
+    }
+    #[test]
+    fn test_32() {
+        let midi_fname = "Marche_aux_Flambeaux.mid";
+        let buf = std::fs::read(midi_fname).unwrap();
+        let smf_buf = midly::Smf::parse(&buf).unwrap();
+        let container = midi_container::MidiContainer::from_buf(&smf_buf).unwrap();
+        assert_eq!(
+            container.track_names(),
+            vec![
+                vec!["Track 0".to_string()],
+                vec!["upper".to_string(), "accordion".to_string()],
+                vec!["lower".to_string(), "accordion".to_string()],
+            ]
+        );
+    }
+    #[test]
+    fn test_33() {
+        let buf = test_fixtures::two_hands();
+        let smf_buf = midly::Smf::parse(&buf).unwrap();
+        let container = midi_container::MidiContainer::from_buf(&smf_buf).unwrap();
+        let info = midi_container::song_info(&container);
+        assert_eq!(info["format"], 1);
+        assert_eq!(info["tempo_changes"][0]["us_per_quarter"], 500_000);
+        assert_eq!(info["time_signature_changes"][0]["numerator"], 4);
+        assert_eq!(info["time_signature_changes"][0]["denominator"], 4);
+        let tracks = info["tracks"].as_array().unwrap();
+        assert_eq!(tracks.len(), 3);
+        assert!(tracks[0]["name"].is_null());
+        assert_eq!(tracks[0]["note_count"], 0);
+        assert!(tracks[0]["lowest_note"].is_null());
+        assert_eq!(tracks[0]["duration_us"], 0);
+        assert_eq!(tracks[1]["name"], "upper");
+        assert_eq!(tracks[1]["instrument"], "piano");
+        assert_eq!(tracks[1]["channels"], serde_json::json!([0]));
+        assert_eq!(tracks[1]["note_count"], 2);
+        assert_eq!(tracks[1]["lowest_note"]["name"], "C4");
+        assert_eq!(tracks[1]["highest_note"]["name"], "E4");
+        assert_eq!(tracks[1]["program_changes"][0]["program"], 0);
+        assert_eq!(tracks[1]["duration_us"], 1_000_000);
+        assert_eq!(tracks[2]["name"], "lower");
+        assert_eq!(tracks[2]["channels"], serde_json::json!([1]));
+        assert_eq!(tracks[2]["note_count"], 1);
+        // The end of track a quarter after the note off is not counted
+        assert_eq!(tracks[2]["duration_us"], 2_000_000);
+        assert_eq!(info["duration_us"], 2_000_000);
     }
 }
@@ -0,0 +1,51 @@
+use std::sync::mpsc;
+
+use log::*;
+use midir::{Ignore, MidiInput, MidiInputConnection};
+
+use crate::midi_ports;
+use crate::midi_sequencer::MidiEvent;
+use crate::time_controller::{TimeListener, TimeListenerTrait};
+
+/// Midi event received from the input port with song position in us
+pub type InputMidiTuple = (i64, MidiEvent);
+
+pub struct MidiInputListener {
+    _conn_in: MidiInputConnection<()>,
+    rx: mpsc::Receiver<InputMidiTuple>,
+}
+
+impl MidiInputListener {
+    pub fn connect(
+        time_listener: TimeListener,
+        opt_port: Option<&str>,
+    ) -> Result<MidiInputListener, Box<dyn std::error::Error>> {
+        trace!("input");
+        let mut midi_in = MidiInput::new("Rusthesia")?;
+        midi_in.ignore(Ignore::All);
+        let port_names = midi_ports::input_port_names(&midi_in);
+        let in_port = midi_ports::select_port(&port_names, opt_port, "input")?;
+        let (tx, rx) = mpsc::channel();
+        let conn_in = midi_in
+            .connect(
+                in_port,
+                "rusthesia",
+                move |_stamp, message, _| {
+                    if let Some(evt) = MidiEvent::from_raw(message) {
+                        let pos_us = time_listener.get_pos_us();
+                        trace!("midi input at {}: {:?}", pos_us, evt);
+                        tx.send((pos_us, evt)).ok();
+                    }
+                },
+                (),
+            )
+            .map_err(|e| format!("{}", e))?;
+        Ok(MidiInputListener {
+            _conn_in: conn_in,
+            rx,
+        })
+    }
+    pub fn try_recv(&self) -> Option<InputMidiTuple> {
+        self.rx.try_recv().ok()
+    }
+}
@@ -0,0 +1,180 @@
+use std::io::{stdin, stdout, Write};
+use std::str::FromStr;
+
+use midir::{MidiInput, MidiOutput};
+
+use crate::part::Part;
+
+/// Select a port by index or by a case insensitive part of its name.
+/// A number is always taken as index, even if it is part of a name.
+/// Without a given port the only available port is chosen, otherwise the
+/// port is read from console.
+pub fn select_port(
+    port_names: &[String],
+    opt_port: Option<&str>,
+    direction: &str,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    if let Some(port) = opt_port {
+        let opt_idx = if !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()) {
+            port.parse::<usize>()
+                .ok()
+                .filter(|idx| *idx < port_names.len())
+        } else {
+            let part = port.to_lowercase();
+            port_names
+                .iter()
+                .position(|name| name.to_lowercase().contains(&part))
+        };
+        return match opt_idx {
+            Some(idx) => {
+                println!("Choosing {} port: {}", direction, port_names[idx]);
+                Ok(idx)
+            }
+            None => Err(format!(
+                "{} port '{}' not found, available are: {}",
+                direction,
+                port,
+                port_names.join(", ")
+            )
+            .into()),
+        };
+    }
+    match port_names.len() {
+        0 => Err(format!("no {} port found", direction).into()),
+        1 => {
+            println!(
+                "Choosing the only available {} port: {}",
+                direction, port_names[0]
+            );
+            Ok(0)
+        }
+        _ => {
+            println!("\nAvailable {} ports:", direction);
+            for (i, name) in port_names.iter().enumerate() {
+                println!("{}: {}", i, name);
+            }
+            print!("Please select {} port: ", direction);
+            stdout().flush()?;
+            let mut input = String::new();
+            stdin().read_line(&mut input)?;
+            let idx: usize = input.trim().parse()?;
+            if idx < port_names.len() {
+                Ok(idx)
+            } else {
+                Err(format!("no {} port {}", direction, idx).into())
+            }
+        }
+    }
+}
+
+/// Part to be played on its own output port given as `<part>=<port>`,
+/// where the part is a track, a channel or both like for `-p` and `-s`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Route {
+    pub part: Part,
+    pub port: String,
+}
+impl FromStr for Route {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("Invalid route '{}', expected <part>=<port>", s);
+        let mut parts = s.splitn(2, '=');
+        let part = parts.next().unwrap().trim();
+        let port = parts.next().map(|port| port.trim()).ok_or_else(err)?;
+        if port.is_empty() {
+            return Err(err());
+        }
+        Ok(Route {
+            part: part.parse::<Part>().map_err(|_| err())?,
+            port: port.to_string(),
+        })
+    }
+}
+
+pub fn input_port_names(midi_in: &MidiInput) -> Vec<String> {
+    (0..midi_in.port_count())
+        .map(|i| midi_in.port_name(i).unwrap_or_else(|_| "?".to_string()))
+        .collect()
+}
+
+pub fn output_port_names(midi_out: &MidiOutput) -> Vec<String> {
+    (0..midi_out.port_count())
+        .map(|i| midi_out.port_name(i).unwrap_or_else(|_| "?".to_string()))
+        .collect()
+}
+
+pub fn list_command() -> Result<(), Box<dyn std::error::Error>> {
+    let midi_in = MidiInput::new("Rusthesia")?;
+    println!("Input ports:");
+    for (i, name) in input_port_names(&midi_in).iter().enumerate() {
+        println!("  {}: {}", i, name);
+    }
+    let midi_out = MidiOutput::new("Rusthesia")?;
+    println!("Output ports:");
+    for (i, name) in output_port_names(&midi_out).iter().enumerate() {
+        println!("  {}: {}", i, name);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::midi_ports::{select_port, Route};
+    use crate::part::{Part, TrackRef};
+
+    #[test]
+    fn test_01() {
+        let names = vec![
+            "IAC-Treiber IAC-Bus 1".to_string(),
+            "FluidSynth virtual port (3776)".to_string(),
+        ];
+        assert_eq!(select_port(&names, Some("1"), "output").unwrap(), 1);
+        assert_eq!(select_port(&names, Some("fluid"), "output").unwrap(), 1);
+        assert_eq!(select_port(&names, Some("IAC"), "output").unwrap(), 0);
+        assert!(select_port(&names, Some("timidity"), "output").is_err());
+        assert!(select_port(&[], None, "output").is_err());
+        assert_eq!(select_port(&names[..1], None, "output").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_02() {
+        assert_eq!(
+            "2=FluidSynth".parse::<Route>(),
+            Ok(Route {
+                part: Part::track(2),
+                port: "FluidSynth".to_string()
+            })
+        );
+        assert_eq!("1 = 0".parse::<Route>().unwrap().port, "0");
+        assert_eq!(
+            "ch1=fluid".parse::<Route>().unwrap().part,
+            Part {
+                track: None,
+                channel: Some(1)
+            }
+        );
+        assert_eq!(
+            "Piano:ch0=fluid".parse::<Route>().unwrap().part,
+            Part {
+                track: Some(TrackRef::Name("Piano".to_string())),
+                channel: Some(0)
+            }
+        );
+        assert!("ch16=fluid".parse::<Route>().is_err());
+        assert!("1".parse::<Route>().is_err());
+        assert!("1=".parse::<Route>().is_err());
+    }
+    #[test]
+    fn test_03() {
+        let names = vec![
+            "IAC-Treiber IAC-Bus 1".to_string(),
+            "FluidSynth virtual port (3776)".to_string(),
+        ];
+        let err = select_port(&names, Some("3"), "output").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("IAC-Treiber IAC-Bus 1, FluidSynth"));
+        assert!(select_port(&names, Some("3776"), "output").is_err());
+        assert_eq!(select_port(&names, Some("(3776)"), "output").unwrap(), 1);
+    }
+}
@@ -1,15 +1,20 @@
 use std::collections::HashSet;
-use std::io::{stdin, stdout, Write};
 use std::sync::mpsc;
 use std::thread;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use log::*;
-use midir::MidiOutput;
+use midir::{MidiOutput, MidiOutputConnection};
 
+use crate::chase;
+use crate::metronome::CountIn;
+use crate::midi_ports;
+use crate::midi_ports::Route;
+use crate::mixer::{Mixer, CHANNEL_VOLUME};
+use crate::part::Part;
 use crate::time_controller::{TimeController, TimeListener, TimeListenerTrait};
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum MidiEvent {
     NoteOn(u8, u8, u8),
     NoteOff(u8, u8, u8),
@@ -49,32 +54,252 @@ impl MidiEvent {
             MidiEvent::ProgramChange(channel, program) => vec![0xc0 + channel, *program],
         }
     }
+    pub fn channel(&self) -> u8 {
+        match *self {
+            MidiEvent::NoteOn(channel, _, _)
+            | MidiEvent::NoteOff(channel, _, _)
+            | MidiEvent::Aftertouch(channel, _, _)
+            | MidiEvent::Controller(channel, _, _)
+            | MidiEvent::ChannelAftertouch(channel, _)
+            | MidiEvent::PitchBend(channel, _)
+            | MidiEvent::ProgramChange(channel, _) => channel & 0x0f,
+        }
+    }
+    pub fn from_raw(msg: &[u8]) -> Option<MidiEvent> {
+        if msg.len() < 2 {
+            return None;
+        }
+        let channel = msg[0] & 0x0f;
+        match (msg[0] & 0xf0, msg.len()) {
+            (0x80, 3) => Some(MidiEvent::NoteOff(channel, msg[1], msg[2])),
+            (0x90, 3) => Some(MidiEvent::NoteOn(channel, msg[1], msg[2])),
+            (0xa0, 3) => Some(MidiEvent::Aftertouch(channel, msg[1], msg[2])),
+            (0xb0, 3) => Some(MidiEvent::Controller(channel, msg[1], msg[2])),
+            (0xc0, 2) => Some(MidiEvent::ProgramChange(channel, msg[1])),
+            (0xd0, 2) => Some(MidiEvent::ChannelAftertouch(channel, msg[1])),
+            (0xe0, 3) => Some(MidiEvent::PitchBend(
+                channel,
+                msg[1] as u16 | (msg[2] as u16) << 7,
+            )),
+            _ => None,
+        }
+    }
 }
 
 pub type RawMidiTuple = (u64, usize, MidiEvent);
 
+enum OutputPort {
+    Index(usize),
+    /// Virtual port with the given name, to which synthesizers can subscribe
+    Virtual(String),
+}
+
 enum MidiSequencerCommand {
     Ping,
-    Connect(usize),
+    /// Connect an output port for the given parts. The first connection
+    /// is used for all other parts and the metronome
+    Connect(OutputPort, Vec<Part>),
     SetPosition(i64),
     SetEvents(Vec<RawMidiTuple>),
     Play(i64),
+    Resume(i64),
     Scale(u16),
+    SetLoop(Option<(i64, i64)>),
+    SetClicks(Vec<RawMidiTuple>),
+    Metronome(bool),
+    SetCountIn(Option<CountIn>),
+    SetMixer(Mixer),
+    Panic,
     Stop,
 }
 
 enum SequencerState {
     Stopped,
     Playing,
-    StartPlaying(i64),
+    /// Start playing at the position with or without count-in. Only after
+    /// a jump to the position all channels are reset and their state is chased.
+    StartPlaying(i64, bool, bool),
     EOF,
 }
 
+/// Output connections with the parts routed to them
+struct Outputs {
+    conns: Vec<(MidiOutputConnection, Vec<Part>)>,
+}
+impl Outputs {
+    /// Index of the connection for the track and channel. Parts without
+    /// route go to the first connection.
+    fn conn_idx(&self, trk_idx: usize, channel: u8) -> usize {
+        self.conns
+            .iter()
+            .position(|(_, parts)| parts.iter().any(|part| part.matches(trk_idx, channel)))
+            .unwrap_or(0)
+    }
+    fn send(&mut self, trk_idx: usize, msg: &[u8]) {
+        if msg.is_empty() || self.conns.is_empty() {
+            return;
+        }
+        let idx = self.conn_idx(trk_idx, msg[0] & 0x0f);
+        self.conns[idx].0.send(msg).unwrap();
+    }
+    fn send_all(&mut self, msg: &[u8]) {
+        for (conn_out, _) in self.conns.iter_mut() {
+            conn_out.send(msg).unwrap();
+        }
+    }
+}
+
+fn release_keys(outputs: &mut Outputs, key_pressed: &mut HashSet<(usize, u8, u8)>) {
+    for (trk_idx, channel, key) in key_pressed.drain() {
+        let evt = MidiEvent::NoteOff(channel as u8, key, 0);
+        let msg = evt.as_raw(trk_idx, None);
+        outputs.send(trk_idx, &msg);
+    }
+}
+
+/// Channels used by the events as bit mask
+fn used_channels(events: &[RawMidiTuple]) -> u16 {
+    events
+        .iter()
+        .fold(0, |mask, (_, _, evt)| mask | 1 << evt.channel())
+}
+
+/// Silence all used channels including notes held by the sustain pedal and
+/// reset their controllers
+fn send_panic(outputs: &mut Outputs, key_pressed: &mut HashSet<(usize, u8, u8)>, channels: u16) {
+    release_keys(outputs, key_pressed);
+    for channel in (0..16).filter(|channel| channels & 1 << channel != 0) {
+        // Sustain off, all notes off, all sound off and reset all controllers
+        for control in [64, 123, 120, 121].iter() {
+            let evt = MidiEvent::Controller(channel, *control, 0);
+            outputs.send_all(&evt.as_raw(0, None));
+        }
+    }
+}
+
+fn open_output(out_port: OutputPort) -> MidiOutputConnection {
+    let midi_out = midir::MidiOutput::new("Rusthesia").unwrap();
+    match out_port {
+        OutputPort::Index(idx) => midi_out.connect(idx, "rusthesia").unwrap(),
+        #[cfg(unix)]
+        OutputPort::Virtual(port_name) => {
+            use midir::os::unix::VirtualOutput;
+            midi_out.create_virtual(&port_name).unwrap()
+        }
+        #[cfg(not(unix))]
+        OutputPort::Virtual(_) => panic!("Virtual ports are not supported"),
+    }
+}
+
+/// Release the keys of tracks, which have been muted meanwhile
+fn release_silenced(
+    outputs: &mut Outputs,
+    key_pressed: &mut HashSet<(usize, u8, u8)>,
+    mixer: &Mixer,
+) {
+    let silenced = key_pressed
+        .iter()
+        .filter(|(trk_idx, _, _)| !mixer.is_audible(*trk_idx))
+        .cloned()
+        .collect::<Vec<_>>();
+    for (trk_idx, channel, key) in silenced.into_iter() {
+        let evt = MidiEvent::NoteOff(channel, key, 0);
+        outputs.send(trk_idx, &evt.as_raw(trk_idx, Some(key_pressed)));
+    }
+}
+
+/// Restore the state of programs and controllers after the already played
+/// events. Each connection gets the state of the tracks routed to it and
+/// the channel volume is scaled by the mixer.
+fn send_chase(outputs: &mut Outputs, played_events: &[RawMidiTuple], mixer: &Mixer) {
+    for idx in 0..outputs.conns.len() {
+        let events = played_events
+            .iter()
+            .filter(|(_, trk_idx, evt)| outputs.conn_idx(*trk_idx, evt.channel()) == idx)
+            .map(|(time_us, trk_idx, evt)| match evt {
+                MidiEvent::Controller(_, CHANNEL_VOLUME, _) => (
+                    *time_us,
+                    *trk_idx,
+                    mixer.apply(*trk_idx, evt).unwrap_or(*evt),
+                ),
+                _ => (*time_us, *trk_idx, *evt),
+            })
+            .collect::<Vec<_>>();
+        for evt in chase::chase_state(events.iter()).iter() {
+            outputs.conns[idx].0.send(&evt.as_raw(0, None)).unwrap();
+        }
+    }
+}
+
+/// Send the channel volume scaled by the new mixer for every track and
+/// channel with changed volume, so a fade takes effect on held notes, too.
+/// Channels without volume event so far are at the default of 100.
+fn send_volumes(
+    outputs: &mut Outputs,
+    events: &[RawMidiTuple],
+    idx: usize,
+    old_mixer: &Mixer,
+    mixer: &Mixer,
+) {
+    let mut changed: Vec<(usize, u8)> = vec![];
+    for (_, trk_idx, evt) in events.iter() {
+        let channel = evt.channel();
+        if changed.contains(&(*trk_idx, channel))
+            || old_mixer.event_volume(*trk_idx, channel) == mixer.event_volume(*trk_idx, channel)
+        {
+            continue;
+        }
+        changed.push((*trk_idx, channel));
+        let volume = events[..idx]
+            .iter()
+            .rev()
+            .find_map(|(_, trk, evt)| match *evt {
+                MidiEvent::Controller(ch, CHANNEL_VOLUME, value)
+                    if trk == trk_idx && ch & 0x0f == channel =>
+                {
+                    Some(value)
+                }
+                _ => None,
+            })
+            .unwrap_or(100);
+        let evt = MidiEvent::Controller(channel, CHANNEL_VOLUME, volume);
+        if let Some(evt) = mixer.apply(*trk_idx, &evt) {
+            outputs.send(*trk_idx, &evt.as_raw(*trk_idx, None));
+        }
+    }
+}
+
+/// Index of the first event not before the given position
+fn index_from(events: &[RawMidiTuple], pos_us: i64) -> usize {
+    events
+        .iter()
+        .position(|evt| evt.0 as i64 >= pos_us)
+        .unwrap_or(events.len())
+}
+
+struct CountingIn {
+    started: Instant,
+    clicks: Vec<RawMidiTuple>,
+    idx: usize,
+    duration_us: u64,
+}
+
 struct MidiSequencerThread {
     control: mpsc::Receiver<MidiSequencerCommand>,
     events: Vec<RawMidiTuple>,
     time_control: TimeController,
     exit_on_eof: bool,
+    /// Playing jumps back from loop end to loop start
+    opt_loop: Option<(i64, i64)>,
+    clicks: Vec<RawMidiTuple>,
+    metronome: bool,
+    count_in: Option<CountIn>,
+    counting_in: Option<CountingIn>,
+    /// Channels to reset on panic as bit mask
+    used_channels: u16,
+    mixer: Mixer,
+    /// Position or events have changed while stopped
+    jumped: bool,
 }
 impl MidiSequencerThread {
     fn new(
@@ -87,27 +312,65 @@ impl MidiSequencerThread {
             events: vec![],
             time_control,
             exit_on_eof,
+            opt_loop: None,
+            clicks: vec![],
+            metronome: false,
+            count_in: None,
+            counting_in: None,
+            used_channels: 0,
+            mixer: Mixer::new(),
+            jumped: true,
+        }
+    }
+    /// Channels of the previous events are kept for panic, because notes
+    /// may still be held
+    fn set_events(&mut self, events: Vec<RawMidiTuple>) {
+        self.used_channels |= used_channels(&events);
+        self.events = events;
+    }
+    /// Count in before playing from pos_us or start playing right away
+    fn start_playing(&mut self, pos_us: i64, with_count_in: bool) {
+        let scale_1000 = self.time_control.get_scaling_1000();
+        let opt_clicks = match (self.count_in.as_ref(), with_count_in) {
+            (Some(count_in), true) => count_in.clicks(pos_us, scale_1000),
+            _ => None,
+        };
+        if let Some((clicks, duration_us)) = opt_clicks {
+            trace!("Count in for {} us", duration_us);
+            self.time_control.stop();
+            self.time_control.set_pos_us(pos_us);
+            self.counting_in = Some(CountingIn {
+                started: Instant::now(),
+                clicks,
+                idx: 0,
+                duration_us,
+            });
+        } else {
+            self.counting_in = None;
+            self.time_control.set_count_in_beats(0);
+            self.time_control.start();
         }
     }
     fn run(&mut self) {
         use crate::midi_sequencer::SequencerState::*;
-        let mut opt_conn_out = None;
+        let mut outputs = Outputs { conns: vec![] };
         let mut idx = 0;
+        let mut click_idx = 0;
         let mut state = EOF;
         let mut key_pressed = HashSet::new();
         loop {
             state = match state {
                 EOF => match self.control.recv() {
                     Err(mpsc::RecvError) => break,
-                    Ok(MidiSequencerCommand::Connect(out_port)) => {
+                    Ok(MidiSequencerCommand::Connect(out_port, tracks)) => {
                         trace!("Opening connection");
-                        let midi_out = midir::MidiOutput::new("Rusthesia").unwrap();
-                        opt_conn_out = Some(midi_out.connect(out_port, "rusthesia").unwrap());
+                        outputs.conns.push((open_output(out_port), tracks));
                         trace!("Connection opened");
                         EOF
                     }
                     Ok(MidiSequencerCommand::Stop) | Ok(MidiSequencerCommand::Ping) => EOF,
-                    Ok(MidiSequencerCommand::Play(pos_us)) => StartPlaying(pos_us),
+                    Ok(MidiSequencerCommand::Play(pos_us)) => StartPlaying(pos_us, true, true),
+                    Ok(MidiSequencerCommand::Resume(pos_us)) => StartPlaying(pos_us, false, false),
                     Ok(MidiSequencerCommand::Scale(new_scaling)) => {
                         self.time_control.set_scaling_1000(new_scaling);
                         EOF
@@ -116,26 +379,85 @@ impl MidiSequencerThread {
                         self.time_control.set_pos_us(pos_us);
                         EOF
                     }
+                    Ok(MidiSequencerCommand::SetLoop(opt_loop)) => {
+                        self.opt_loop = opt_loop;
+                        EOF
+                    }
+                    Ok(MidiSequencerCommand::SetClicks(clicks)) => {
+                        self.used_channels |= used_channels(&clicks);
+                        self.clicks = clicks;
+                        EOF
+                    }
+                    Ok(MidiSequencerCommand::Metronome(on)) => {
+                        self.metronome = on;
+                        EOF
+                    }
+                    Ok(MidiSequencerCommand::SetCountIn(count_in)) => {
+                        self.count_in = count_in;
+                        EOF
+                    }
+                    Ok(MidiSequencerCommand::SetMixer(mixer)) => {
+                        self.mixer = mixer;
+                        EOF
+                    }
+                    Ok(MidiSequencerCommand::Panic) => {
+                        send_panic(&mut outputs, &mut key_pressed, self.used_channels);
+                        EOF
+                    }
                     Ok(MidiSequencerCommand::SetEvents(events)) => {
-                        self.events = events;
+                        self.set_events(events);
+                        self.jumped = true;
                         Stopped
                     }
                 },
                 Stopped => match self.control.recv() {
                     Err(mpsc::RecvError) => break,
-                    Ok(MidiSequencerCommand::Connect(_out_port)) => panic!("Not connected"),
+                    Ok(MidiSequencerCommand::Connect(..)) => panic!("Not connected"),
                     Ok(MidiSequencerCommand::Ping) => Stopped,
-                    Ok(MidiSequencerCommand::Play(pos_us)) => StartPlaying(pos_us),
+                    Ok(MidiSequencerCommand::Play(pos_us)) => {
+                        StartPlaying(pos_us, true, self.jumped)
+                    }
+                    Ok(MidiSequencerCommand::Resume(pos_us)) => {
+                        StartPlaying(pos_us, false, self.jumped)
+                    }
                     Ok(MidiSequencerCommand::Scale(new_scaling)) => {
                         self.time_control.set_scaling_1000(new_scaling);
                         Stopped
                     }
                     Ok(MidiSequencerCommand::SetPosition(pos_us)) => {
                         self.time_control.set_pos_us(pos_us);
+                        self.jumped = true;
+                        Stopped
+                    }
+                    Ok(MidiSequencerCommand::SetLoop(opt_loop)) => {
+                        self.opt_loop = opt_loop;
+                        Stopped
+                    }
+                    Ok(MidiSequencerCommand::SetClicks(clicks)) => {
+                        self.used_channels |= used_channels(&clicks);
+                        self.clicks = clicks;
+                        Stopped
+                    }
+                    Ok(MidiSequencerCommand::Metronome(on)) => {
+                        self.metronome = on;
+                        Stopped
+                    }
+                    Ok(MidiSequencerCommand::SetCountIn(count_in)) => {
+                        self.count_in = count_in;
+                        Stopped
+                    }
+                    Ok(MidiSequencerCommand::SetMixer(mixer)) => {
+                        send_volumes(&mut outputs, &self.events, idx, &self.mixer, &mixer);
+                        self.mixer = mixer;
+                        Stopped
+                    }
+                    Ok(MidiSequencerCommand::Panic) => {
+                        send_panic(&mut outputs, &mut key_pressed, self.used_channels);
                         Stopped
                     }
                     Ok(MidiSequencerCommand::SetEvents(events)) => {
-                        self.events = events;
+                        self.set_events(events);
+                        self.jumped = true;
                         Stopped
                     }
                     Ok(MidiSequencerCommand::Stop) => Stopped,
@@ -143,42 +465,80 @@ impl MidiSequencerThread {
                 Playing => match self.control.try_recv() {
                     Err(mpsc::TryRecvError::Disconnected) => break,
                     Err(mpsc::TryRecvError::Empty) => Playing,
-                    Ok(MidiSequencerCommand::Connect(_out_port)) => panic!("Not connected"),
+                    Ok(MidiSequencerCommand::Connect(..)) => panic!("Not connected"),
                     Ok(MidiSequencerCommand::Ping) => Playing,
-                    Ok(MidiSequencerCommand::Play(pos_us)) => StartPlaying(pos_us),
+                    Ok(MidiSequencerCommand::Play(pos_us)) => StartPlaying(pos_us, true, true),
+                    Ok(MidiSequencerCommand::Resume(pos_us)) => StartPlaying(pos_us, false, false),
                     Ok(MidiSequencerCommand::Scale(new_scaling)) => {
                         self.time_control.set_scaling_1000(new_scaling);
                         Playing
                     }
-                    Ok(MidiSequencerCommand::SetPosition(pos_us)) => StartPlaying(pos_us),
+                    Ok(MidiSequencerCommand::SetPosition(pos_us)) => {
+                        StartPlaying(pos_us, true, true)
+                    }
+                    Ok(MidiSequencerCommand::SetLoop(opt_loop)) => {
+                        self.opt_loop = opt_loop;
+                        Playing
+                    }
+                    Ok(MidiSequencerCommand::SetClicks(clicks)) => {
+                        self.used_channels |= used_channels(&clicks);
+                        self.clicks = clicks;
+                        click_idx = index_from(&self.clicks, self.time_control.get_pos_us());
+                        Playing
+                    }
+                    Ok(MidiSequencerCommand::Metronome(on)) => {
+                        self.metronome = on;
+                        Playing
+                    }
+                    Ok(MidiSequencerCommand::SetCountIn(count_in)) => {
+                        self.count_in = count_in;
+                        Playing
+                    }
+                    Ok(MidiSequencerCommand::SetMixer(mixer)) => {
+                        send_volumes(&mut outputs, &self.events, idx, &self.mixer, &mixer);
+                        self.mixer = mixer;
+                        release_silenced(&mut outputs, &mut key_pressed, &self.mixer);
+                        Playing
+                    }
                     Ok(MidiSequencerCommand::SetEvents(events)) => {
-                        self.events = events;
-                        StartPlaying(0)
+                        self.set_events(events);
+                        StartPlaying(self.time_control.get_pos_us(), false, true)
+                    }
+                    Ok(MidiSequencerCommand::Panic) => {
+                        send_panic(&mut outputs, &mut key_pressed, self.used_channels);
+                        send_chase(&mut outputs, &self.events[..idx], &self.mixer);
+                        Playing
                     }
                     Ok(MidiSequencerCommand::Stop) => {
+                        self.counting_in = None;
+                        self.time_control.set_count_in_beats(0);
                         self.time_control.stop();
-                        if let Some(ref mut conn_out) = opt_conn_out.as_mut() {
-                            for (trk_idx, channel, key) in key_pressed.drain() {
-                                let evt = MidiEvent::NoteOff(channel as u8, key, 0);
-                                let msg = evt.as_raw(trk_idx, None);
-                                conn_out.send(&msg).unwrap();
-                            }
-                        }
+                        // Silence sustained notes too and chase again on continuing
+                        send_panic(&mut outputs, &mut key_pressed, self.used_channels);
+                        self.jumped = true;
                         Stopped
                     }
                 },
-                StartPlaying(_) => panic!("StartPlaying should not be reachable here"),
+                StartPlaying(..) => panic!("StartPlaying should not be reachable here"),
             };
 
             state = match state {
                 Stopped => Stopped,
                 EOF => EOF,
-                StartPlaying(pos_us) => {
+                StartPlaying(pos_us, with_count_in, jumped) => {
+                    self.jumped = false;
                     idx = 0;
                     self.time_control.set_pos_us(pos_us as i64);
                     while idx < self.events.len() && pos_us >= self.events[idx].0 as i64 {
                         idx += 1;
                     }
+                    click_idx = index_from(&self.clicks, pos_us);
+                    if jumped {
+                        send_panic(&mut outputs, &mut key_pressed, self.used_channels);
+                        send_chase(&mut outputs, &self.events[..idx], &self.mixer);
+                    } else {
+                        release_keys(&mut outputs, &mut key_pressed);
+                    }
                     if idx >= self.events.len() {
                         self.time_control.stop();
                         if self.exit_on_eof {
@@ -186,31 +546,94 @@ impl MidiSequencerThread {
                         }
                         EOF
                     } else {
-                        self.time_control.start();
+                        self.start_playing(pos_us, with_count_in);
                         Playing
                     }
                 }
+                Playing if self.counting_in.is_some() => {
+                    let counting_in = self.counting_in.as_mut().unwrap();
+                    let elapsed_us = counting_in.started.elapsed().as_micros() as u64;
+                    while counting_in.idx < counting_in.clicks.len()
+                        && elapsed_us >= counting_in.clicks[counting_in.idx].0
+                    {
+                        let (_, trk_idx, evt) = &counting_in.clicks[counting_in.idx];
+                        let msg = evt.as_raw(*trk_idx, Some(&mut key_pressed));
+                        outputs.send(*trk_idx, &msg);
+                        counting_in.idx += 1;
+                    }
+                    if elapsed_us >= counting_in.duration_us {
+                        self.counting_in = None;
+                        self.time_control.set_count_in_beats(0);
+                        self.time_control.start();
+                    } else {
+                        // Count down including the current beat
+                        let beats = counting_in
+                            .clicks
+                            .iter()
+                            .filter(|(time_us, _, evt)| {
+                                *time_us > elapsed_us && matches!(evt, MidiEvent::NoteOn(..))
+                            })
+                            .count();
+                        self.time_control.set_count_in_beats(beats as u32 + 1);
+                        let next_us = counting_in
+                            .clicks
+                            .get(counting_in.idx)
+                            .map_or(counting_in.duration_us, |click| {
+                                click.0.min(counting_in.duration_us)
+                            });
+                        let sleep_ms = ((next_us - elapsed_us) / 1000).min(20);
+                        sleep(Duration::from_millis(sleep_ms));
+                    }
+                    Playing
+                }
                 Playing => {
                     let pos_us = self.time_control.get_pos_us();
-                    if let Some(ref mut conn_out) = opt_conn_out.as_mut() {
-                        while idx < self.events.len() && pos_us >= self.events[idx].0 as i64 {
-                            let msg = self.events[idx]
-                                .2
-                                .as_raw(self.events[idx].1, Some(&mut key_pressed));
-                            if msg.len() > 0 {
-                                conn_out.send(&msg).unwrap();
-                            }
-                            idx += 1;
+                    let end_pos = self.opt_loop.map(|(_, loop_end)| loop_end);
+                    while idx < self.events.len()
+                        && pos_us >= self.events[idx].0 as i64
+                        && end_pos.map_or(true, |end| end > self.events[idx].0 as i64)
+                    {
+                        let (_, trk_idx, evt) = &self.events[idx];
+                        if let Some(evt) = self.mixer.apply(*trk_idx, evt) {
+                            let msg = evt.as_raw(*trk_idx, Some(&mut key_pressed));
+                            outputs.send(*trk_idx, &msg);
                         }
+                        idx += 1;
                     }
-                    if idx >= self.events.len() {
-                        self.time_control.stop();
-                        if self.exit_on_eof {
-                            break;
+                    while click_idx < self.clicks.len()
+                        && pos_us >= self.clicks[click_idx].0 as i64
+                        && end_pos.map_or(true, |end| end > self.clicks[click_idx].0 as i64)
+                    {
+                        let (_, trk_idx, evt) = &self.clicks[click_idx];
+                        // Clicks already on are released even with metronome switched off
+                        if self.metronome || !matches!(evt, MidiEvent::NoteOn(..)) {
+                            let msg = evt.as_raw(*trk_idx, Some(&mut key_pressed));
+                            outputs.send(*trk_idx, &msg);
                         }
-                        EOF
-                    } else {
-                        let next_pos = self.events[idx].0 as i64;
+                        click_idx += 1;
+                    }
+                    if let Some((loop_start, loop_end)) = self.opt_loop {
+                        if pos_us >= loop_end {
+                            trace!("Loop back to {} us", loop_start);
+                            send_panic(&mut outputs, &mut key_pressed, self.used_channels);
+                            self.time_control.set_pos_us(loop_start);
+                            self.time_control.add_loop_pass();
+                            idx = index_from(&self.events, loop_start);
+                            click_idx = index_from(&self.clicks, loop_start);
+                            send_chase(&mut outputs, &self.events[..idx], &self.mixer);
+                            self.start_playing(loop_start, true);
+                        }
+                    }
+                    let opt_next_pos = match (self.events.get(idx), end_pos) {
+                        (Some(evt), Some(end)) => Some(end.min(evt.0 as i64)),
+                        (Some(evt), None) => Some(evt.0 as i64),
+                        (None, opt_end) => opt_end,
+                    };
+                    let opt_next_pos = match (opt_next_pos, self.clicks.get(click_idx)) {
+                        (Some(next_pos), Some(click)) => Some(next_pos.min(click.0 as i64)),
+                        (opt_next_pos, _) => opt_next_pos,
+                    };
+                    if let Some(next_pos) = opt_next_pos {
                         let opt_sleep_ms = self.time_control.ms_till_pos(next_pos);
                         if let Some(sleep_ms) = opt_sleep_ms {
                             let sleep_ms = sleep_ms.min(20);
@@ -218,11 +641,18 @@ impl MidiSequencerThread {
                             sleep(Duration::from_millis(sleep_ms as u64));
                         }
                         Playing
+                    } else {
+                        self.time_control.stop();
+                        if self.exit_on_eof {
+                            break;
+                        }
+                        EOF
                     }
                 }
             }
         }
-        if let Some(conn_out) = opt_conn_out {
+        send_panic(&mut outputs, &mut key_pressed, self.used_channels);
+        for (conn_out, _) in outputs.conns.into_iter() {
             conn_out.close();
             trace!("Connection closed");
         }
@@ -264,43 +694,110 @@ impl MidiSequencer {
     pub fn play(&self, pos_us: i64) {
         self.control.send(MidiSequencerCommand::Play(pos_us)).ok();
     }
+    /// Continue playing after a stop at the same position without count-in
+    /// and without chasing the channel state
+    pub fn resume(&self, pos_us: i64) {
+        self.control.send(MidiSequencerCommand::Resume(pos_us)).ok();
+    }
     pub fn set_scaling_1000(&self, new_scale: u16) {
         self.control
             .send(MidiSequencerCommand::Scale(new_scale))
             .ok();
     }
+    pub fn set_loop(&self, opt_loop: Option<(i64, i64)>) {
+        self.control
+            .send(MidiSequencerCommand::SetLoop(opt_loop))
+            .ok();
+    }
+    pub fn set_clicks(&self, clicks: Vec<RawMidiTuple>) {
+        self.control
+            .send(MidiSequencerCommand::SetClicks(clicks))
+            .ok();
+    }
+    pub fn set_count_in(&self, count_in: Option<CountIn>) {
+        self.control
+            .send(MidiSequencerCommand::SetCountIn(count_in))
+            .ok();
+    }
+    pub fn set_metronome(&self, on: bool) {
+        self.control.send(MidiSequencerCommand::Metronome(on)).ok();
+    }
+    pub fn set_mixer(&self, mixer: Mixer) {
+        self.control
+            .send(MidiSequencerCommand::SetMixer(mixer))
+            .ok();
+    }
     pub fn stop(&self) {
         self.control.send(MidiSequencerCommand::Stop).ok();
     }
-    pub fn connect(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn panic(&self) {
+        self.control.send(MidiSequencerCommand::Panic).ok();
+    }
+    pub fn connect(&mut self, opt_port: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
         trace!("output");
         let midi_out = MidiOutput::new("Rusthesia")?;
-        // Get an output port (read from console if multiple are available)
-        let out_port = match midi_out.port_count() {
-            0 => return Err("no output port found".into()),
-            1 => {
-                println!(
-                    "Choosing the only available output port: {}",
-                    midi_out.port_name(0).unwrap()
-                );
-                0
-            }
-            _ => {
-                println!("\nAvailable output ports:");
-                for i in 0..midi_out.port_count() {
-                    println!("{}: {}", i, midi_out.port_name(i).unwrap());
-                }
-                print!("Please select output port: ");
-                stdout().flush()?;
-                let mut input = String::new();
-                stdin().read_line(&mut input)?;
-                input.trim().parse()?
-            }
-        };
+        let port_names = midi_ports::output_port_names(&midi_out);
+        let out_port = midi_ports::select_port(&port_names, opt_port, "output")?;
         drop(midi_out);
         self.control
-            .send(MidiSequencerCommand::Connect(out_port))
+            .send(MidiSequencerCommand::Connect(
+                OutputPort::Index(out_port),
+                vec![],
+            ))
+            .ok();
+        Ok(())
+    }
+    /// Connect the output ports of the routes in addition to the port
+    /// for all other parts. Parts routed to the same port share the
+    /// connection. Track names need to be resolved before.
+    pub fn connect_routes(&mut self, routes: &[Route]) -> Result<(), Box<dyn std::error::Error>> {
+        let port_names = midi_ports::output_port_names(&MidiOutput::new("Rusthesia")?);
+        let mut port_parts: Vec<(usize, Vec<Part>)> = vec![];
+        for route in routes.iter() {
+            let out_port = midi_ports::select_port(&port_names, Some(&route.port), "output")?;
+            match port_parts.iter_mut().find(|(port, _)| *port == out_port) {
+                Some((_, parts)) => parts.push(route.part.clone()),
+                None => port_parts.push((out_port, vec![route.part.clone()])),
+            }
+        }
+        for (out_port, parts) in port_parts.into_iter() {
+            self.control
+                .send(MidiSequencerCommand::Connect(
+                    OutputPort::Index(out_port),
+                    parts,
+                ))
+                .ok();
+        }
+        Ok(())
+    }
+    /// Create a virtual output port instead of connecting to an existing one
+    pub fn connect_virtual(&mut self, port_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if cfg!(not(unix)) {
+            return Err("virtual output port is not supported on this platform".into());
+        }
+        println!("Creating virtual output port: {}", port_name);
+        self.control
+            .send(MidiSequencerCommand::Connect(
+                OutputPort::Virtual(port_name.to_string()),
+                vec![],
+            ))
             .ok();
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::midi_sequencer::{used_channels, MidiEvent};
+
+    #[test]
+    fn test_01() {
+        let events = vec![
+            (0, 1, MidiEvent::ProgramChange(0, 5)),
+            (0, 1, MidiEvent::NoteOn(9, 36, 64)),
+            (10, 2, MidiEvent::Controller(15, 64, 127)),
+        ];
+        assert_eq!(used_channels(&events), 0x8201);
+        assert_eq!(used_channels(&[]), 0);
+    }
+}
@@ -0,0 +1,209 @@
+use crate::metronome::METRONOME_TRACK;
+use crate::midi_sequencer::MidiEvent;
+use crate::part::{Part, TrackRef};
+
+pub const CHANNEL_VOLUME: u8 = 7;
+
+/// Adds the item, if not contained, otherwise removes it. Returns true,
+/// if the item is contained now.
+pub fn toggle<T: PartialEq>(items: &mut Vec<T>, item: T) -> bool {
+    match items.iter().position(|i| *i == item) {
+        Some(pos) => {
+            items.remove(pos);
+            false
+        }
+        None => {
+            items.push(item);
+            true
+        }
+    }
+}
+
+/// Mute, solo and volume of the tracks applied to the played events
+#[derive(Clone, Debug, Default)]
+pub struct Mixer {
+    muted: Vec<usize>,
+    soloed: Vec<usize>,
+    /// Volume in percent of the parts, all other events are at 100%
+    volumes: Vec<(Part, u8)>,
+}
+impl Mixer {
+    pub fn new() -> Mixer {
+        Mixer::default()
+    }
+    /// Returns true, if the track is muted now
+    pub fn toggle_mute(&mut self, trk_idx: usize) -> bool {
+        toggle(&mut self.muted, trk_idx)
+    }
+    /// Returns true, if the track is soloed now
+    pub fn toggle_solo(&mut self, trk_idx: usize) -> bool {
+        toggle(&mut self.soloed, trk_idx)
+    }
+    pub fn volume(&self, part: &Part) -> u8 {
+        self.volumes
+            .iter()
+            .find(|(p, _)| p == part)
+            .map_or(100, |(_, percent)| *percent)
+    }
+    pub fn set_volume(&mut self, part: &Part, percent: u8) {
+        self.volumes.retain(|(p, _)| p != part);
+        if percent != 100 {
+            self.volumes.push((part.clone(), percent));
+        }
+    }
+    /// Volume of the events of the track and channel given by the first
+    /// matching part
+    pub fn event_volume(&self, trk_idx: usize, channel: u8) -> u8 {
+        self.volumes
+            .iter()
+            .find(|(part, _)| part.matches(trk_idx, channel))
+            .map_or(100, |(_, percent)| *percent)
+    }
+    /// The metronome is never silenced
+    pub fn is_audible(&self, trk_idx: usize) -> bool {
+        trk_idx == METRONOME_TRACK
+            || !self.muted.contains(&trk_idx)
+                && (self.soloed.is_empty() || self.soloed.contains(&trk_idx))
+    }
+    /// The event to be sent for the track or None, if the track is silenced
+    /// or the note scaled down to zero velocity. Note off is always sent to
+    /// release keys pressed before muting.
+    pub fn apply(&self, trk_idx: usize, evt: &MidiEvent) -> Option<MidiEvent> {
+        let volume = self.event_volume(trk_idx, evt.channel());
+        let scale = |value: u8| (value as u32 * volume as u32 / 100).min(127) as u8;
+        match *evt {
+            MidiEvent::NoteOn(channel, key, velocity) if velocity > 0 => {
+                match (self.is_audible(trk_idx), scale(velocity)) {
+                    (true, velocity) if velocity > 0 => {
+                        Some(MidiEvent::NoteOn(channel, key, velocity))
+                    }
+                    _ => None,
+                }
+            }
+            MidiEvent::Controller(channel, CHANNEL_VOLUME, value) => {
+                Some(MidiEvent::Controller(channel, CHANNEL_VOLUME, scale(value)))
+            }
+            evt => Some(evt),
+        }
+    }
+    pub fn text(&self) -> Option<String> {
+        let list = |tracks: &[usize]| {
+            let mut tracks = tracks.to_vec();
+            tracks.sort();
+            tracks
+                .iter()
+                .map(|trk| trk.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        let mut parts = vec![];
+        if !self.muted.is_empty() {
+            parts.push(format!("Mute {}", list(&self.muted)));
+        }
+        if !self.soloed.is_empty() {
+            parts.push(format!("Solo {}", list(&self.soloed)));
+        }
+        for (part, percent) in self.volumes.iter() {
+            let name = match (&part.track, part.channel) {
+                (Some(TrackRef::Index(trk_idx)), None) => format!("Track {}", trk_idx),
+                (Some(TrackRef::Index(trk_idx)), Some(channel)) => {
+                    format!("Track {} channel {}", trk_idx, channel)
+                }
+                (_, Some(channel)) => format!("Channel {}", channel),
+                (_, None) => "All".to_string(),
+            };
+            parts.push(format!("{} {}%", name, percent));
+        }
+        if parts.is_empty() {
+            None
+        } else {
+            Some(parts.join(", "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metronome::METRONOME_TRACK;
+    use crate::midi_sequencer::MidiEvent::*;
+    use crate::mixer::{toggle, Mixer};
+    use crate::part::Part;
+
+    #[test]
+    fn test_01() {
+        let mut mixer = Mixer::new();
+        assert_eq!(
+            mixer.apply(1, &NoteOn(0, 60, 100)),
+            Some(NoteOn(0, 60, 100))
+        );
+        assert!(mixer.toggle_mute(1));
+        assert_eq!(mixer.apply(1, &NoteOn(0, 60, 100)), None);
+        assert_eq!(mixer.apply(1, &NoteOff(0, 60, 0)), Some(NoteOff(0, 60, 0)));
+        assert_eq!(
+            mixer.apply(2, &NoteOn(1, 60, 100)),
+            Some(NoteOn(1, 60, 100))
+        );
+        assert!(mixer.toggle_solo(3));
+        assert_eq!(mixer.apply(2, &NoteOn(1, 60, 100)), None);
+        assert_eq!(
+            mixer.apply(3, &NoteOn(2, 60, 100)),
+            Some(NoteOn(2, 60, 100))
+        );
+        assert!(mixer.is_audible(METRONOME_TRACK));
+        assert_eq!(mixer.text(), Some("Mute 1, Solo 3".to_string()));
+        assert!(!mixer.toggle_mute(1));
+        assert!(!mixer.toggle_solo(3));
+        assert!(mixer.is_audible(1));
+        assert_eq!(mixer.text(), None);
+        let mut parts = vec![Part::track(0)];
+        assert!(toggle(&mut parts, Part::track(1)));
+        assert!(!toggle(&mut parts, Part::track(0)));
+        assert_eq!(parts, vec![Part::track(1)]);
+    }
+
+    #[test]
+    fn test_02() {
+        let mut mixer = Mixer::new();
+        mixer.set_volume(&Part::track(2), 50);
+        assert_eq!(mixer.apply(2, &NoteOn(0, 60, 100)), Some(NoteOn(0, 60, 50)));
+        assert_eq!(mixer.apply(2, &NoteOn(0, 60, 1)), None);
+        assert_eq!(
+            mixer.apply(2, &Controller(0, 7, 90)),
+            Some(Controller(0, 7, 45))
+        );
+        assert_eq!(
+            mixer.apply(2, &Controller(0, 10, 90)),
+            Some(Controller(0, 10, 90))
+        );
+        assert_eq!(
+            mixer.apply(1, &NoteOn(0, 60, 100)),
+            Some(NoteOn(0, 60, 100))
+        );
+        mixer.set_volume(&Part::track(2), 0);
+        assert_eq!(mixer.volume(&Part::track(2)), 0);
+        assert_eq!(mixer.apply(2, &NoteOn(0, 60, 100)), None);
+        assert_eq!(mixer.text(), Some("Track 2 0%".to_string()));
+        mixer.set_volume(&Part::track(2), 100);
+        assert_eq!(mixer.text(), None);
+    }
+
+    #[test]
+    fn test_03() {
+        // Fading the right hand of a format 0 file on channel 1
+        let mut mixer = Mixer::new();
+        let part = "ch1".parse::<Part>().unwrap();
+        mixer.set_volume(&part, 50);
+        assert_eq!(mixer.volume(&part), 50);
+        assert_eq!(mixer.apply(0, &NoteOn(1, 60, 100)), Some(NoteOn(1, 60, 50)));
+        assert_eq!(
+            mixer.apply(0, &Controller(1, 7, 100)),
+            Some(Controller(1, 7, 50))
+        );
+        assert_eq!(
+            mixer.apply(0, &NoteOn(0, 48, 100)),
+            Some(NoteOn(0, 48, 100))
+        );
+        assert_eq!(mixer.event_volume(0, 0), 100);
+        assert_eq!(mixer.text(), Some("Channel 1 50%".to_string()));
+    }
+}
@@ -0,0 +1,292 @@
+use std::str::FromStr;
+
+/// Track given by index or by a name pattern, which is resolved from the
+/// track and instrument names of the midi file
+#[derive(Clone, Debug, PartialEq)]
+pub enum TrackRef {
+    Index(usize),
+    Name(String),
+}
+
+/// Part of the midi file to be shown or played. It is given by track,
+/// midi channel or both as `<track>`, `ch<channel>` or `<track>:ch<channel>`.
+/// The channel allows to select the hands of format 0 files with one track.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Part {
+    pub track: Option<TrackRef>,
+    pub channel: Option<u8>,
+}
+impl Part {
+    pub fn track(trk_idx: usize) -> Part {
+        Part {
+            track: Some(TrackRef::Index(trk_idx)),
+            channel: None,
+        }
+    }
+    /// Track names need to be resolved before, otherwise nothing matches
+    pub fn matches(&self, trk_idx: usize, channel: u8) -> bool {
+        let track_matches = match self.track {
+            None => true,
+            Some(TrackRef::Index(track)) => track == trk_idx,
+            Some(TrackRef::Name(_)) => false,
+        };
+        track_matches && self.channel.map_or(true, |ch| ch == channel)
+    }
+}
+
+/// True for `ch` followed by digits or nothing, which is never a track name
+fn looks_like_channel(s: &str) -> bool {
+    s.strip_prefix("ch")
+        .map_or(false, |digits| digits.chars().all(|c| c.is_ascii_digit()))
+}
+
+fn parse_channel(s: &str) -> Option<u8> {
+    if !s.starts_with("ch") {
+        return None;
+    }
+    match s[2..].parse::<u8>() {
+        Ok(channel) if channel < 16 => Some(channel),
+        _ => None,
+    }
+}
+
+fn parse_track(s: &str) -> Option<TrackRef> {
+    if s.is_empty() {
+        None
+    } else if let Ok(trk_idx) = s.parse::<usize>() {
+        Some(TrackRef::Index(trk_idx))
+    } else {
+        Some(TrackRef::Name(s.to_string()))
+    }
+}
+
+impl FromStr for Part {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || {
+            format!(
+                "Invalid part '{}', expected <track>, ch<channel> or <track>:ch<channel>",
+                s
+            )
+        };
+        // Track names may contain ':', so the channel is split off at the end.
+        // A number or an invalid channel after ':' is an error.
+        if let Some(pos) = s.rfind(':') {
+            let suffix = &s[pos + 1..];
+            if looks_like_channel(suffix) || suffix.parse::<usize>().is_ok() {
+                return Ok(Part {
+                    track: Some(parse_track(&s[..pos]).ok_or_else(err)?),
+                    channel: Some(parse_channel(suffix).ok_or_else(err)?),
+                });
+            }
+        }
+        if looks_like_channel(s) {
+            return Ok(Part {
+                track: None,
+                channel: Some(parse_channel(s).ok_or_else(err)?),
+            });
+        }
+        Ok(Part {
+            track: Some(parse_track(s).ok_or_else(err)?),
+            channel: None,
+        })
+    }
+}
+
+/// Case insensitive match of a pattern with wildcards '*' and '?'
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.to_lowercase().chars().collect::<Vec<_>>();
+    let text = text.to_lowercase().chars().collect::<Vec<_>>();
+    // Position in text and pattern after the last '*' for backtracking
+    let mut backtrack: Option<(usize, usize)> = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == '*' {
+            p += 1;
+            backtrack = Some((p, t));
+        } else if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if let Some((bp, bt)) = backtrack {
+            p = bp;
+            t = bt + 1;
+            backtrack = Some((bp, bt + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Replace the track names of the parts by the indices of all matching
+/// tracks. track_names contains the names of every track.
+pub fn resolve(parts: &[Part], track_names: &[Vec<String>]) -> Result<Vec<Part>, String> {
+    let mut resolved = vec![];
+    for part in parts.iter() {
+        match part.track {
+            Some(TrackRef::Name(ref pattern)) => {
+                let matching = track_names
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, names)| names.iter().any(|name| glob_match(pattern, name)))
+                    .map(|(trk_idx, _)| trk_idx)
+                    .collect::<Vec<_>>();
+                if matching.is_empty() {
+                    let available = track_names
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, names)| !names.is_empty())
+                        .map(|(trk_idx, names)| format!("{}: {}", trk_idx, names.join("/")))
+                        .collect::<Vec<_>>();
+                    return Err(format!(
+                        "No track matches '{}', available are: {}",
+                        pattern,
+                        available.join(", ")
+                    ));
+                }
+                for trk_idx in matching.into_iter() {
+                    resolved.push(Part {
+                        track: Some(TrackRef::Index(trk_idx)),
+                        channel: part.channel,
+                    });
+                }
+            }
+            _ => resolved.push(part.clone()),
+        }
+    }
+    Ok(resolved)
+}
+
+/// True, if an event of the track and channel belongs to one of the parts
+pub fn is_selected(parts: &[Part], trk_idx: usize, channel: u8) -> bool {
+    parts.iter().any(|part| part.matches(trk_idx, channel))
+}
+
+/// True, if an event of the track and channel is to be played. With
+/// no_play_shown the events of the shown parts are left to be played by hand,
+/// while the other channels of a shown track are still played.
+pub fn is_played(
+    play_parts: &[Part],
+    show_parts: &[Part],
+    no_play_shown: bool,
+    trk_idx: usize,
+    channel: u8,
+) -> bool {
+    is_selected(play_parts, trk_idx, channel)
+        && !(no_play_shown && is_selected(show_parts, trk_idx, channel))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::part::{glob_match, is_played, is_selected, resolve, Part, TrackRef};
+
+    fn part(s: &str) -> Part {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_01() {
+        assert_eq!("2".parse::<Part>(), Ok(Part::track(2)));
+        assert_eq!(
+            "ch9".parse::<Part>(),
+            Ok(Part {
+                track: None,
+                channel: Some(9)
+            })
+        );
+        assert_eq!(
+            "0:ch1".parse::<Part>(),
+            Ok(Part {
+                track: Some(TrackRef::Index(0)),
+                channel: Some(1)
+            })
+        );
+        assert!(":ch2".parse::<Part>().is_err());
+        assert!("".parse::<Part>().is_err());
+        assert!("ch16".parse::<Part>().is_err());
+        assert!("1:2".parse::<Part>().is_err());
+        assert!("ch".parse::<Part>().is_err());
+        assert!("0:ch16".parse::<Part>().is_err());
+    }
+
+    #[test]
+    fn test_02() {
+        let parts = vec!["1".parse().unwrap(), "0:ch2".parse().unwrap()];
+        assert!(is_selected(&parts, 1, 5));
+        assert!(is_selected(&parts, 0, 2));
+        assert!(!is_selected(&parts, 0, 1));
+        assert!(!is_selected(&parts, 2, 2));
+        let parts = vec!["ch3".parse().unwrap()];
+        assert!(is_selected(&parts, 0, 3));
+        assert!(is_selected(&parts, 4, 3));
+        assert!(!is_selected(&parts, 0, 4));
+        assert!(!is_selected(&[], 0, 0));
+    }
+
+    #[test]
+    fn test_03() {
+        assert_eq!(
+            "*piano*:ch1".parse::<Part>(),
+            Ok(Part {
+                track: Some(TrackRef::Name("*piano*".to_string())),
+                channel: Some(1)
+            })
+        );
+        assert_eq!(
+            "choir".parse::<Part>().unwrap().track,
+            Some(TrackRef::Name("choir".to_string()))
+        );
+        assert_eq!(
+            "a:b".parse::<Part>().unwrap().track,
+            Some(TrackRef::Name("a:b".to_string()))
+        );
+        assert!(glob_match("*piano*", "Grand Piano"));
+        assert!(glob_match("upper", "Upper"));
+        assert!(glob_match("l?wer", "lower"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "aXbYbc"));
+        assert!(!glob_match("upper", "upper2"));
+        assert!(!glob_match("*piano", "piano left"));
+    }
+
+    #[test]
+    fn test_04() {
+        let names = vec![
+            vec!["Track 0".to_string()],
+            vec!["upper".to_string(), "accordion".to_string()],
+            vec!["lower".to_string(), "accordion".to_string()],
+        ];
+        let parts = vec!["upper".parse().unwrap(), "0".parse().unwrap()];
+        assert_eq!(
+            resolve(&parts, &names),
+            Ok(vec![Part::track(1), Part::track(0)])
+        );
+        let parts = vec!["ACCORDION:ch3".parse().unwrap()];
+        let resolved = resolve(&parts, &names).unwrap();
+        assert_eq!(resolved.len(), 2);
+        assert_eq!(resolved[1].track, Some(TrackRef::Index(2)));
+        assert_eq!(resolved[1].channel, Some(3));
+        let parts = vec!["piano".parse().unwrap()];
+        assert_eq!(
+            resolve(&parts, &names),
+            Err("No track matches 'piano', available are: 0: Track 0, \
+                 1: upper/accordion, 2: lower/accordion"
+                .to_string())
+        );
+    }
+
+    #[test]
+    fn test_05() {
+        let play = vec![part("0")];
+        let show = vec![part("0:ch0")];
+        assert!(is_played(&play, &show, false, 0, 0));
+        assert!(!is_played(&play, &show, true, 0, 0));
+        assert!(is_played(&play, &show, true, 0, 1));
+        assert!(!is_played(&play, &show, true, 1, 1));
+        let show = vec![part("ch1"), part("2")];
+        let play = vec![part("1"), part("2")];
+        assert!(is_played(&play, &show, true, 1, 0));
+        assert!(!is_played(&play, &show, true, 1, 1));
+        assert!(!is_played(&play, &show, true, 2, 0));
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::HashSet;
+
+use log::*;
+
+use crate::midi_sequencer::{MidiEvent, RawMidiTuple};
+
+/// Notes starting within this time are treated as one chord
+const CHORD_WINDOW_US: u64 = 50_000;
+/// Hold a bit ahead of the chord, so its notes are not yet sent by the sequencer
+const HOLD_AHEAD_US: i64 = 20_000;
+/// Keys pressed this early before a chord are accepted for that chord
+const EARLY_HIT_US: i64 = 300_000;
+
+struct Chord {
+    time_us: u64,
+    keys: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum WaitAction {
+    None,
+    Hold(i64),
+    Resume,
+}
+
+/// Wait mode for practicing: Playback is held at every chord of the shown
+/// tracks, until all its keys have been hit on the midi input.
+pub struct WaitMode {
+    chords: Vec<Chord>,
+    next_chord: usize,
+    hits: HashSet<u8>,
+    holding: bool,
+}
+impl WaitMode {
+    pub fn new(show_events: &[RawMidiTuple]) -> WaitMode {
+        WaitMode {
+            chords: WaitMode::chords(show_events),
+            next_chord: 0,
+            hits: HashSet::new(),
+            holding: false,
+        }
+    }
+    fn chords(show_events: &[RawMidiTuple]) -> Vec<Chord> {
+        let mut chords: Vec<Chord> = vec![];
+        for (time_us, _, evt) in show_events.iter() {
+            if let MidiEvent::NoteOn(_channel, key, pressure) = evt {
+                if *pressure == 0 {
+                    continue;
+                }
+                if let Some(chord) = chords.last_mut() {
+                    if chord.time_us + CHORD_WINDOW_US > *time_us {
+                        if !chord.keys.contains(key) {
+                            chord.keys.push(*key);
+                        }
+                        continue;
+                    }
+                }
+                chords.push(Chord {
+                    time_us: *time_us,
+                    keys: vec![*key],
+                });
+            }
+        }
+        debug!("Wait mode with {} chords", chords.len());
+        chords
+    }
+    /// Takes over changed show events. Keys already hit for the current
+    /// chord still count, if they are part of the new chord.
+    pub fn reload(&mut self, show_events: &[RawMidiTuple], pos_us: i64) {
+        let holding = self.holding;
+        let hits = std::mem::take(&mut self.hits);
+        self.chords = WaitMode::chords(show_events);
+        self.seek(pos_us);
+        self.holding = holding;
+        if let Some(chord) = self.chords.get(self.next_chord) {
+            self.hits = hits
+                .into_iter()
+                .filter(|key| chord.keys.contains(key))
+                .collect();
+        }
+    }
+    /// Continue with the first chord not before the new position
+    pub fn seek(&mut self, pos_us: i64) {
+        self.next_chord = self
+            .chords
+            .iter()
+            .position(|chord| chord.time_us as i64 + HOLD_AHEAD_US > pos_us)
+            .unwrap_or(self.chords.len());
+        self.hits.clear();
+        self.holding = false;
+    }
+    /// Keys not in the current chord are ignored
+    pub fn key_pressed(&mut self, pos_us: i64, key: u8) {
+        if let Some(chord) = self.chords.get(self.next_chord) {
+            if pos_us + EARLY_HIT_US >= chord.time_us as i64 && chord.keys.contains(&key) {
+                self.hits.insert(key);
+            }
+        }
+    }
+    pub fn update(&mut self, pos_us: i64) -> WaitAction {
+        let mut action = WaitAction::None;
+        while let Some(chord) = self.chords.get(self.next_chord) {
+            if chord.keys.iter().all(|key| self.hits.contains(key)) {
+                trace!("Chord at {} us completed", chord.time_us);
+                self.hits.clear();
+                self.next_chord += 1;
+                if self.holding {
+                    self.holding = false;
+                    action = WaitAction::Resume;
+                }
+                continue;
+            }
+            if pos_us + HOLD_AHEAD_US >= chord.time_us as i64 {
+                self.holding = true;
+                return WaitAction::Hold(pos_us.min(chord.time_us as i64));
+            }
+            break;
+        }
+        action
+    }
+}
+
+/// Tempo trainer raising the speed after every repetition of a loop.
+pub struct TempoTrainer {
+    step_1000: u16,
+    target_1000: u16,
+    only_clean: bool,
+}
+impl TempoTrainer {
+    pub fn new(step_1000: u16, target_1000: u16, only_clean: bool) -> TempoTrainer {
+        TempoTrainer {
+            step_1000,
+            target_1000,
+            only_clean,
+        }
+    }
+    /// Speed for the next repetition. With only_clean set, the speed is
+    /// raised only after a repetition played without mistakes.
+    pub fn next_scaling_1000(&self, scale_1000: u16, clean: bool) -> u16 {
+        if scale_1000 >= self.target_1000 || (self.only_clean && !clean) {
+            scale_1000
+        } else {
+            (scale_1000 + self.step_1000).min(self.target_1000)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::midi_sequencer::MidiEvent;
+    use crate::practice::{TempoTrainer, WaitAction, WaitMode};
+
+    fn events() -> Vec<(u64, usize, MidiEvent)> {
+        vec![
+            (1_000_000, 1, MidiEvent::NoteOn(0, 60, 64)),
+            (1_010_000, 1, MidiEvent::NoteOn(0, 64, 64)),
+            (1_500_000, 1, MidiEvent::NoteOff(0, 60, 0)),
+            (1_500_000, 1, MidiEvent::NoteOn(0, 64, 0)),
+            (2_000_000, 1, MidiEvent::NoteOn(0, 67, 64)),
+        ]
+    }
+
+    #[test]
+    fn test_01() {
+        let mut wm = WaitMode::new(&events());
+        assert_eq!(wm.update(0), WaitAction::None);
+        assert_eq!(wm.update(990_000), WaitAction::Hold(990_000));
+        // Still holding without any key pressed
+        assert_eq!(wm.update(990_000), WaitAction::Hold(990_000));
+        wm.key_pressed(990_000, 60);
+        assert_eq!(wm.update(990_000), WaitAction::Hold(990_000));
+        // A wrong key does not count for the chord
+        wm.key_pressed(990_000, 62);
+        assert_eq!(wm.update(990_000), WaitAction::Hold(990_000));
+        wm.key_pressed(990_000, 64);
+        assert_eq!(wm.update(990_000), WaitAction::Resume);
+        // No longer holding at the completed chord
+        assert_eq!(wm.update(990_000), WaitAction::None);
+        assert_eq!(wm.update(1_500_000), WaitAction::None);
+        assert_eq!(wm.update(2_100_000), WaitAction::Hold(2_000_000));
+    }
+
+    #[test]
+    fn test_02() {
+        let mut wm = WaitMode::new(&events());
+        // Keys pressed too early do not count
+        wm.key_pressed(0, 60);
+        wm.key_pressed(0, 64);
+        assert_eq!(wm.update(1_000_000), WaitAction::Hold(1_000_000));
+        // Keys pressed in time before the chord let playback continue
+        wm.seek(1_500_000);
+        wm.key_pressed(1_900_000, 67);
+        assert_eq!(wm.update(1_990_000), WaitAction::None);
+        assert_eq!(wm.update(2_500_000), WaitAction::None);
+    }
+
+    #[test]
+    fn test_03() {
+        let trainer = TempoTrainer::new(50, 1000, false);
+        assert_eq!(trainer.next_scaling_1000(600, false), 650);
+        assert_eq!(trainer.next_scaling_1000(980, true), 1000);
+        assert_eq!(trainer.next_scaling_1000(1200, true), 1200);
+        let trainer = TempoTrainer::new(50, 900, true);
+        assert_eq!(trainer.next_scaling_1000(600, false), 600);
+        assert_eq!(trainer.next_scaling_1000(600, true), 650);
+    }
+
+    #[test]
+    fn test_04() {
+        let mut wm = WaitMode::new(&events());
+        assert_eq!(wm.update(990_000), WaitAction::Hold(990_000));
+        wm.key_pressed(990_000, 60);
+        // Reload while holding with the chord transposed by one semitone
+        let shifted = events()
+            .into_iter()
+            .map(|(time_us, trk, evt)| match evt {
+                MidiEvent::NoteOn(c, key, vel) if key == 64 => {
+                    (time_us, trk, MidiEvent::NoteOn(c, key + 1, vel))
+                }
+                evt => (time_us, trk, evt),
+            })
+            .collect::<Vec<_>>();
+        wm.reload(&shifted, 990_000);
+        assert_eq!(wm.update(990_000), WaitAction::Hold(990_000));
+        wm.key_pressed(990_000, 65);
+        assert_eq!(wm.update(990_000), WaitAction::Resume);
+    }
+}
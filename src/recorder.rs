@@ -0,0 +1,172 @@
+use std::io::{Error, ErrorKind};
+
+use log::*;
+
+use crate::midi_container::MidiContainer;
+use crate::midi_sequencer::MidiEvent;
+use crate::tempo_map::TempoMap;
+
+/// Records the events from midi input with their song position.
+///
+/// The recording is written as additional track to a copy of the played
+/// midi file. So the original tempo map applies and the take can be
+/// replayed alongside the original.
+pub struct Recorder {
+    out_fname: String,
+    events: Vec<(i64, MidiEvent)>,
+}
+impl Recorder {
+    pub fn new(out_fname: &str) -> Recorder {
+        Recorder {
+            out_fname: out_fname.to_string(),
+            events: vec![],
+        }
+    }
+    pub fn record(&mut self, pos_us: i64, evt: MidiEvent) {
+        self.events.push((pos_us, evt));
+    }
+    pub fn write(&self, midi_fname: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let buf = std::fs::read(midi_fname)?;
+        let smf_buf = midly::Smf::parse(&buf)
+            .map_err(|e| Error::new(ErrorKind::Other, format!("{:?}", e)))?;
+        let container = MidiContainer::from_buf(&smf_buf)?;
+        let tempo_map = container.tempo_map();
+        let out = build_smf(&buf, &tempo_map, &self.events)?;
+        std::fs::write(&self.out_fname, out)?;
+        println!(
+            "Recorded {} events to {}",
+            self.events.len(),
+            self.out_fname
+        );
+        Ok(())
+    }
+}
+
+fn write_vlq(buf: &mut Vec<u8>, value: u32) {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    let mut value = value >> 7;
+    while value > 0 {
+        bytes.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.reverse();
+    buf.extend_from_slice(&bytes);
+}
+
+fn write_chunk(buf: &mut Vec<u8>, id: &[u8], data: &[u8]) {
+    buf.extend_from_slice(id);
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+fn encode_track(tempo_map: &TempoMap, events: &[(i64, MidiEvent)]) -> Vec<u8> {
+    let mut timed = events
+        .iter()
+        .map(|(pos_us, evt)| (tempo_map.us_to_tick((*pos_us).max(0) as u64), evt))
+        .collect::<Vec<_>>();
+    // Seeking backwards during recording yields events out of order
+    timed.sort_by_key(|(tick, _)| *tick);
+
+    let mut data = vec![];
+    let name = b"Recording";
+    data.extend_from_slice(&[0x00, 0xff, 0x03, name.len() as u8]);
+    data.extend_from_slice(name);
+    let mut last_tick = 0;
+    for (tick, evt) in timed.into_iter() {
+        write_vlq(&mut data, (tick - last_tick) as u32);
+        data.extend_from_slice(&evt.as_raw(0, None));
+        last_tick = tick;
+    }
+    data.extend_from_slice(&[0x00, 0xff, 0x2f, 0x00]);
+    data
+}
+
+/// Copies header and tracks of the original midi file and appends the
+/// recorded events as new track of a format 1 file.
+fn build_smf(
+    original: &[u8],
+    tempo_map: &TempoMap,
+    events: &[(i64, MidiEvent)],
+) -> Result<Vec<u8>, Error> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "invalid midi file");
+    let read_u32 = |pos: usize| -> Result<usize, Error> {
+        let bytes = original.get(pos..pos + 4).ok_or_else(invalid)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    };
+    if !original.starts_with(b"MThd") {
+        return Err(invalid());
+    }
+    let header_len = read_u32(4)?;
+    let division = original.get(12..14).ok_or_else(invalid)?;
+
+    let mut tracks = vec![];
+    let mut pos = 8 + header_len;
+    while pos + 8 <= original.len() {
+        let len = read_u32(pos + 4)?;
+        let chunk = original.get(pos..pos + 8 + len).ok_or_else(invalid)?;
+        if &chunk[0..4] == b"MTrk" {
+            tracks.push(chunk);
+        } else {
+            debug!("Skip unknown chunk {:?}", &chunk[0..4]);
+        }
+        pos += 8 + len;
+    }
+
+    let mut out = vec![];
+    let nr_of_tracks = tracks.len() as u16 + 1;
+    let mut header = vec![0x00, 0x01];
+    header.extend_from_slice(&nr_of_tracks.to_be_bytes());
+    header.extend_from_slice(division);
+    write_chunk(&mut out, b"MThd", &header);
+    for chunk in tracks.into_iter() {
+        out.extend_from_slice(chunk);
+    }
+    write_chunk(&mut out, b"MTrk", &encode_track(tempo_map, events));
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::midi_container::MidiContainer;
+    use crate::midi_sequencer::MidiEvent;
+    use crate::recorder;
+
+    #[test]
+    fn test_01() {
+        let mut buf = vec![];
+        recorder::write_vlq(&mut buf, 0);
+        recorder::write_vlq(&mut buf, 0x7f);
+        recorder::write_vlq(&mut buf, 0x80);
+        recorder::write_vlq(&mut buf, 192_000);
+        assert_eq!(buf, vec![0x00, 0x7f, 0x81, 0x00, 0x8b, 0xdc, 0x00]);
+    }
+
+    #[test]
+    fn test_02() {
+        let midi_fname = "Marche_aux_Flambeaux.mid";
+        let original = std::fs::read(midi_fname).unwrap();
+        let smf_buf = midly::Smf::parse(&original).unwrap();
+        let container = MidiContainer::from_buf(&smf_buf).unwrap();
+        let tempo_map = container.tempo_map();
+
+        // Tempo is 545454 us per 384 ticks
+        let events = vec![
+            (1_000_000, MidiEvent::NoteOff(0, 60, 0)),
+            (545_454, MidiEvent::NoteOn(0, 60, 64)),
+            (-500_000, MidiEvent::Controller(0, 64, 0)),
+        ];
+        let buf = recorder::build_smf(&original, &tempo_map, &events).unwrap();
+        let smf_buf = midly::Smf::parse(&buf).unwrap();
+        let container = MidiContainer::from_buf(&smf_buf).unwrap();
+        assert_eq!(container.nr_of_tracks(), 4);
+        assert_eq!(container.iter().filter(|e| e.1 < 3).count(), 2423);
+        assert_eq!(
+            container
+                .iter()
+                .filter(|e| e.1 == 3)
+                .map(|e| e.0)
+                .collect::<Vec<_>>(),
+            vec![0, 0, 384, 704, 704]
+        );
+    }
+}
@@ -0,0 +1,328 @@
+use std::io::{Error, ErrorKind, Write};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+
+use log::*;
+use sdl2::image::SaveSurface;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
+use sdl2::surface::Surface;
+
+use crate::app_control::AppControl;
+use crate::draw_engine;
+use crate::midi_container::MidiContainer;
+use crate::tempo_map::SongPosition;
+
+const ROWS_PER_S: u32 = 100;
+const WATERFALL_TEX_HEIGHT: u32 = 1000;
+
+/// Destination of the rendered frames
+pub enum RenderOutput {
+    /// Directory for numbered png files
+    Png(String),
+    /// Video file encoded by ffmpeg
+    Video(String),
+}
+
+/// Rendering of the waterfall without a window
+pub struct RenderSettings {
+    pub output: RenderOutput,
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+    /// Time visible in the waterfall, otherwise the speed of the window is used
+    pub lookahead_us: Option<u64>,
+    /// Render only one frame at this position
+    pub at: Option<SongPosition>,
+}
+
+/// Arguments for ffmpeg to encode raw rgb frames from stdin. The frame
+/// rate is fixed, so the video runs in sync with the song from time 0.
+fn ffmpeg_args(fname: &str, width: u32, height: u32, fps: u32) -> Vec<String> {
+    let size = format!("{}x{}", width, height);
+    let fps = fps.to_string();
+    [
+        "-y",
+        "-loglevel",
+        "error",
+        "-f",
+        "rawvideo",
+        "-pixel_format",
+        "rgb24",
+        "-video_size",
+        &size,
+        "-framerate",
+        &fps,
+        "-i",
+        "-",
+        "-an",
+        "-pix_fmt",
+        "yuv420p",
+        fname,
+    ]
+    .iter()
+    .map(|arg| arg.to_string())
+    .collect()
+}
+
+/// Receives the pixels of the rendered frames
+enum FrameSink {
+    Png(String),
+    Video(String, Child),
+}
+impl FrameSink {
+    fn open(settings: &RenderSettings) -> Result<FrameSink, Box<dyn std::error::Error>> {
+        match settings.output {
+            RenderOutput::Png(ref dir) => {
+                std::fs::create_dir_all(dir)?;
+                Ok(FrameSink::Png(dir.clone()))
+            }
+            RenderOutput::Video(ref fname) => {
+                let child = Command::new("ffmpeg")
+                    .args(ffmpeg_args(
+                        fname,
+                        settings.width,
+                        settings.height,
+                        settings.fps,
+                    ))
+                    .stdin(Stdio::piped())
+                    .spawn()
+                    .map_err(|e| format!("Cannot start ffmpeg: {}", e))?;
+                Ok(FrameSink::Video(fname.clone(), child))
+            }
+        }
+    }
+    fn write(
+        &mut self,
+        frame: usize,
+        pixels: &mut [u8],
+        width: u32,
+        height: u32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            FrameSink::Png(dir) => {
+                // Only a surface can be saved as png, so the pixels are copied
+                let frame_surface =
+                    Surface::from_data(pixels, width, height, 3 * width, PixelFormatEnum::RGB24)?;
+                let fname = Path::new(dir).join(format!("frame_{:06}.png", frame));
+                frame_surface.save(&fname)?;
+            }
+            FrameSink::Video(_, child) => {
+                child
+                    .stdin
+                    .as_mut()
+                    .ok_or("ffmpeg stdin is closed")?
+                    .write_all(pixels)?;
+            }
+        }
+        Ok(())
+    }
+    /// Returns the name of the written directory or file
+    fn finish(self) -> Result<String, Box<dyn std::error::Error>> {
+        match self {
+            FrameSink::Png(dir) => Ok(dir),
+            FrameSink::Video(fname, mut child) => {
+                // Closing stdin lets ffmpeg finish the file
+                drop(child.stdin.take());
+                let status = child.wait()?;
+                if !status.success() {
+                    return Err(format!("ffmpeg failed with {}", status).into());
+                }
+                Ok(fname)
+            }
+        }
+    }
+}
+
+/// Positions of the frames at a fixed frame rate up to end_us
+fn frame_positions(fps: u32, end_us: u64) -> Vec<i64> {
+    (0..)
+        .map(|frame| frame * 1_000_000 / fps.max(1) as i64)
+        .take_while(|pos_us| *pos_us <= end_us as i64)
+        .collect()
+}
+
+/// Render the shown tracks onto a software canvas stepping a simulated
+/// clock and write the frames as numbered png files or as video
+pub fn render(
+    control: &mut AppControl,
+    settings: &RenderSettings,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let buf = std::fs::read(control.midi_fname())?;
+    let smf_buf =
+        midly::Smf::parse(&buf).map_err(|e| Error::new(ErrorKind::Other, format!("{:?}", e)))?;
+    let container = MidiContainer::from_buf(&smf_buf)?;
+    control.resolve_parts(&container.track_names())?;
+    let (show_events, _) = AppControl::read_midi_file(
+        control.midi_fname(),
+        control.left_key(),
+        control.right_key(),
+        control.shift_key(),
+        control.show_tracks().clone(),
+        vec![],
+        false,
+    )?;
+    let maxtime_us = match show_events.last() {
+        Some(evt) => evt.0,
+        None => return Err("no notes to render in the shown tracks".into()),
+    };
+    let end_tick = container
+        .iter()
+        .map(|(tick, _, _)| tick)
+        .last()
+        .unwrap_or(0);
+    let tempo_map = container.tempo_map();
+    let grid = tempo_map.grid(end_tick);
+    let fingering = control.read_fingering(&container, &tempo_map)?;
+    let positions = match settings.at {
+        Some(pos) => {
+            let pos_us = pos
+                .to_us(&tempo_map)
+                .ok_or("bar positions need a midi file with metrical timing")?;
+            vec![pos_us as i64]
+        }
+        None => frame_positions(settings.fps, maxtime_us + 1_000_000),
+    };
+
+    let (width, height) = (settings.width, settings.height);
+    let keyboard =
+        AppControl::build_keyboard(width as u16, control.left_key(), control.right_key())
+            .map_err(|err_str| Error::new(ErrorKind::Other, err_str))?;
+    let nr_of_keys = control.right_key() - control.left_key() + 1;
+    let waterfall_overlap = 2 * width / nr_of_keys as u32;
+    let waterfall_net_height = WATERFALL_TEX_HEIGHT - waterfall_overlap;
+    let waterfall_height = height
+        .checked_sub(keyboard.height as u32)
+        .ok_or("height is too small for the keyboard")?;
+    let rows_per_s = match settings.lookahead_us {
+        Some(lookahead_us) => {
+            (waterfall_height as u64 * 1_000_000 / lookahead_us.max(1)).max(1) as u32
+        }
+        None => ROWS_PER_S,
+    };
+
+    let mut canvas = Surface::new(width, height, PixelFormatEnum::RGB24)?.into_canvas()?;
+    let texture_creator = canvas.texture_creator();
+    let ttf_context = sdl2::ttf::init()?;
+    let font = match control.font_path() {
+        Some(ref path) if control.note_names().is_some() || !fingering.is_empty() => {
+            Some(ttf_context.load_font(path, draw_engine::label_font_size(&keyboard))?)
+        }
+        _ => None,
+    };
+    let labels = draw_engine::Labels {
+        font: font.as_ref(),
+        texture_creator: &texture_creator,
+        note_names: control.note_names(),
+        fingering: &fingering,
+        shift_key: control.shift_key(),
+    };
+    let mut textures = vec![];
+    for key_texture in draw_engine::KEY_TEXTURES.iter() {
+        let mut texture = texture_creator.create_texture_target(
+            texture_creator.default_pixel_format(),
+            width,
+            keyboard.height as u32,
+        )?;
+        canvas.with_texture_canvas(&mut texture, |tex_canvas| {
+            draw_engine::draw_keyboard(&keyboard, tex_canvas, *key_texture, &labels).ok();
+        })?;
+        textures.push(texture);
+    }
+    let rows = (maxtime_us * rows_per_s as u64 + 999_999) / 1_000_000;
+    let nr_of_textures =
+        ((rows + waterfall_net_height as u64 - 1) / waterfall_net_height as u64) as u32;
+    for i in 0..nr_of_textures {
+        let mut texture = texture_creator.create_texture_target(
+            texture_creator.default_pixel_format(),
+            width,
+            WATERFALL_TEX_HEIGHT,
+        )?;
+        canvas.with_texture_canvas(&mut texture, |tex_canvas| {
+            draw_engine::draw_waterfall(
+                &keyboard,
+                tex_canvas,
+                i,
+                i * waterfall_net_height,
+                waterfall_net_height,
+                waterfall_overlap,
+                rows_per_s,
+                &show_events,
+                &grid,
+                &labels,
+            );
+        })?;
+        textures.push(texture);
+    }
+
+    let mut sink = FrameSink::open(settings)?;
+    for (frame, pos_us) in positions.iter().enumerate() {
+        trace!("Render frame {} at {} us", frame, pos_us);
+        canvas.set_draw_color(Color::RGB(50, 50, 50));
+        canvas.clear();
+        let dst_rec = Rect::new(0, waterfall_height as i32, width, keyboard.height as u32);
+        canvas.copy(
+            &textures[draw_engine::KeyTexture::Unpressed as usize],
+            None,
+            dst_rec,
+        )?;
+        let mut draw_commands = draw_engine::get_pressed_key_rectangles(
+            &keyboard,
+            waterfall_height,
+            *pos_us,
+            &show_events,
+            None,
+        );
+        draw_commands.append(&mut draw_engine::copy_waterfall_to_screen(
+            textures.len() - draw_engine::WATERFALL_TEXTURE,
+            width,
+            waterfall_height,
+            waterfall_net_height,
+            waterfall_overlap,
+            rows_per_s,
+            *pos_us,
+        ));
+        for cmd in draw_commands.into_iter() {
+            match cmd {
+                draw_engine::DrawCommand::CopyToScreen {
+                    src_texture,
+                    src_rect,
+                    dst_rect,
+                } => {
+                    canvas.copy(&textures[src_texture], src_rect, dst_rect)?;
+                }
+            }
+        }
+        canvas.present();
+        let mut pixels = canvas.read_pixels(None, PixelFormatEnum::RGB24)?;
+        sink.write(frame, &mut pixels, width, height)?;
+    }
+    let target = sink.finish()?;
+    println!("{} frames written to {}", positions.len(), target);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::renderer::{ffmpeg_args, frame_positions};
+
+    #[test]
+    fn test_01() {
+        assert_eq!(
+            frame_positions(4, 1_000_000),
+            vec![0, 250_000, 500_000, 750_000, 1_000_000]
+        );
+        assert_eq!(frame_positions(30, 0), vec![0]);
+        assert_eq!(frame_positions(30, 100_000).len(), 4);
+    }
+
+    #[test]
+    fn test_02() {
+        let args = ffmpeg_args("out.mp4", 640, 360, 25);
+        let pos = args.iter().position(|arg| arg == "-video_size").unwrap();
+        assert_eq!(args[pos + 1], "640x360");
+        let pos = args.iter().position(|arg| arg == "-framerate").unwrap();
+        assert_eq!(args[pos + 1], "25");
+        assert_eq!(args.last().unwrap(), "out.mp4");
+    }
+}
@@ -0,0 +1,351 @@
+use log::*;
+use serde_json::json;
+
+use crate::midi_sequencer::{MidiEvent, RawMidiTuple};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum NoteState {
+    Pending,
+    Hit {
+        timing_error_us: i64,
+        velocity_error: i16,
+    },
+    Missed,
+    Skipped,
+}
+
+#[derive(Clone)]
+struct ExpectedNote {
+    time_us: i64,
+    key: u8,
+    vel: u8,
+    state: NoteState,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ScoreSummary {
+    pub hits: usize,
+    pub missed: usize,
+    pub extra: usize,
+    pub mean_timing_error_us: i64,
+    pub mean_abs_timing_error_us: i64,
+    pub mean_velocity_error: i16,
+    pub mean_abs_velocity_error: i16,
+}
+impl ScoreSummary {
+    pub fn accuracy_percent(&self) -> usize {
+        let total = self.hits + self.missed + self.extra;
+        if total == 0 {
+            100
+        } else {
+            self.hits * 100 / total
+        }
+    }
+}
+
+/// Scoring of the notes played on midi input against the shown tracks.
+///
+/// A played note matches the nearest expected note of the same key within
+/// the tolerance window. Expected notes without match are missed and played
+/// notes without match are extra. Results of earlier passes over a part
+/// of the song, which is played again after a loop or a seek back, are kept.
+pub struct Scoring {
+    tolerance_us: i64,
+    notes: Vec<ExpectedNote>,
+    extra: Vec<(i64, u8)>,
+    earlier_notes: Vec<ExpectedNote>,
+    earlier_extra: Vec<(i64, u8)>,
+}
+impl Scoring {
+    pub fn new(show_events: &[RawMidiTuple], tolerance_us: i64) -> Scoring {
+        Scoring {
+            tolerance_us,
+            notes: Scoring::expected_notes(show_events),
+            extra: vec![],
+            earlier_notes: vec![],
+            earlier_extra: vec![],
+        }
+    }
+    fn expected_notes(show_events: &[RawMidiTuple]) -> Vec<ExpectedNote> {
+        show_events
+            .iter()
+            .filter_map(|(time_us, _, evt)| match evt {
+                MidiEvent::NoteOn(_channel, key, vel) if *vel > 0 => Some(ExpectedNote {
+                    time_us: *time_us as i64,
+                    key: *key,
+                    vel: *vel,
+                    state: NoteState::Pending,
+                }),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    }
+    /// Takes over changed show events. The state of a note is kept, if
+    /// the same key is expected at the same time, and new notes before
+    /// pos_us are not scored.
+    pub fn reload(&mut self, show_events: &[RawMidiTuple], pos_us: i64) {
+        let mut notes = Scoring::expected_notes(show_events);
+        for note in notes.iter_mut() {
+            if let Some(old_note) = self
+                .notes
+                .iter()
+                .find(|old| old.time_us == note.time_us && old.key == note.key)
+            {
+                note.state = old_note.state;
+            } else if note.time_us < pos_us {
+                note.state = NoteState::Skipped;
+            }
+        }
+        self.notes = notes;
+    }
+    pub fn note_played(&mut self, pos_us: i64, key: u8, vel: u8) {
+        let tolerance_us = self.tolerance_us;
+        let opt_note = self
+            .notes
+            .iter_mut()
+            .filter(|note| note.key == key && note.state == NoteState::Pending)
+            .filter(|note| (note.time_us - pos_us).abs() <= tolerance_us)
+            .min_by_key(|note| (note.time_us - pos_us).abs());
+        if let Some(note) = opt_note {
+            note.state = NoteState::Hit {
+                timing_error_us: pos_us - note.time_us,
+                velocity_error: vel as i16 - note.vel as i16,
+            };
+            trace!("Hit {} at {}: {:?}", key, note.time_us, note.state);
+        } else {
+            trace!("Extra {} at {}", key, pos_us);
+            self.extra.push((pos_us, key));
+        }
+    }
+    /// Notes not played until the tolerance window has passed are missed
+    pub fn update(&mut self, pos_us: i64) {
+        for note in self.notes.iter_mut() {
+            if note.state == NoteState::Pending && note.time_us + self.tolerance_us < pos_us {
+                note.state = NoteState::Missed;
+            }
+        }
+    }
+    /// Notes jumped over are not scored and notes from the new position on
+    /// are scored again. Their results so far are kept as earlier pass.
+    pub fn seek(&mut self, pos_us: i64) {
+        for note in self.notes.iter_mut() {
+            if note.time_us >= pos_us {
+                if note.state != NoteState::Pending && note.state != NoteState::Skipped {
+                    self.earlier_notes.push(note.clone());
+                }
+                note.state = NoteState::Pending;
+            } else if note.state == NoteState::Pending {
+                note.state = NoteState::Skipped;
+            }
+        }
+        let (earlier_extra, extra): (Vec<_>, Vec<_>) = self
+            .extra
+            .drain(..)
+            .partition(|(time_us, _)| *time_us >= pos_us);
+        self.earlier_extra.extend(earlier_extra);
+        self.extra = extra;
+    }
+    /// All notes within the time range have been hit and no extra notes
+    /// have been played
+    pub fn is_clean(&self, start_us: i64, end_us: i64) -> bool {
+        let in_range = |time_us: i64| time_us >= start_us && time_us < end_us;
+        self.notes.iter().all(|note| match note.state {
+            NoteState::Hit { .. } => true,
+            _ => !in_range(note.time_us),
+        }) && !self.extra.iter().any(|(time_us, _)| in_range(*time_us))
+    }
+    pub fn summary(&self) -> ScoreSummary {
+        let mut summary = ScoreSummary::default();
+        let mut sum_timing_error_us = 0;
+        let mut sum_abs_timing_error_us = 0;
+        let mut sum_velocity_error = 0;
+        let mut sum_abs_velocity_error = 0;
+        for note in self.earlier_notes.iter().chain(self.notes.iter()) {
+            match note.state {
+                NoteState::Hit {
+                    timing_error_us,
+                    velocity_error,
+                } => {
+                    summary.hits += 1;
+                    sum_timing_error_us += timing_error_us;
+                    sum_abs_timing_error_us += timing_error_us.abs();
+                    sum_velocity_error += velocity_error as i64;
+                    sum_abs_velocity_error += velocity_error.abs() as i64;
+                }
+                NoteState::Missed => summary.missed += 1,
+                NoteState::Pending | NoteState::Skipped => (),
+            }
+        }
+        summary.extra = self.earlier_extra.len() + self.extra.len();
+        if summary.hits > 0 {
+            let hits = summary.hits as i64;
+            summary.mean_timing_error_us = sum_timing_error_us / hits;
+            summary.mean_abs_timing_error_us = sum_abs_timing_error_us / hits;
+            summary.mean_velocity_error = (sum_velocity_error / hits) as i16;
+            summary.mean_abs_velocity_error = (sum_abs_velocity_error / hits) as i16;
+        }
+        summary
+    }
+    pub fn overlay_text(&self) -> String {
+        let summary = self.summary();
+        format!(
+            "Accuracy {}%  Hits {}  Missed {}  Extra {}  Timing {:+}ms",
+            summary.accuracy_percent(),
+            summary.hits,
+            summary.missed,
+            summary.extra,
+            summary.mean_timing_error_us / 1000,
+        )
+    }
+    pub fn print_summary(&self) {
+        let summary = self.summary();
+        println!("Score:");
+        println!("  Accuracy: {}%", summary.accuracy_percent());
+        println!("  Hit notes: {}", summary.hits);
+        println!("  Missed notes: {}", summary.missed);
+        println!("  Extra notes: {}", summary.extra);
+        println!(
+            "  Timing error: {:+} ms average, {} ms average absolute",
+            summary.mean_timing_error_us / 1000,
+            summary.mean_abs_timing_error_us / 1000
+        );
+        println!(
+            "  Velocity error: {:+} average, {} average absolute",
+            summary.mean_velocity_error, summary.mean_abs_velocity_error
+        );
+    }
+    pub fn to_json(&self) -> serde_json::Value {
+        let summary = self.summary();
+        let notes = self
+            .earlier_notes
+            .iter()
+            .chain(self.notes.iter())
+            .filter_map(|note| match note.state {
+                NoteState::Hit {
+                    timing_error_us,
+                    velocity_error,
+                } => Some(json!({
+                    "time_us": note.time_us,
+                    "key": note.key,
+                    "result": "hit",
+                    "timing_error_us": timing_error_us,
+                    "velocity_error": velocity_error,
+                })),
+                NoteState::Missed => Some(json!({
+                    "time_us": note.time_us,
+                    "key": note.key,
+                    "result": "missed",
+                })),
+                NoteState::Pending | NoteState::Skipped => None,
+            })
+            .collect::<Vec<_>>();
+        let extra = self
+            .earlier_extra
+            .iter()
+            .chain(self.extra.iter())
+            .map(|(time_us, key)| json!({ "time_us": time_us, "key": key }))
+            .collect::<Vec<_>>();
+        json!({
+            "accuracy_percent": summary.accuracy_percent(),
+            "hits": summary.hits,
+            "missed": summary.missed,
+            "extra": summary.extra,
+            "mean_timing_error_us": summary.mean_timing_error_us,
+            "mean_abs_timing_error_us": summary.mean_abs_timing_error_us,
+            "mean_velocity_error": summary.mean_velocity_error,
+            "mean_abs_velocity_error": summary.mean_abs_velocity_error,
+            "notes": notes,
+            "extra_notes": extra,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::midi_sequencer::MidiEvent;
+    use crate::scoring::Scoring;
+
+    fn events() -> Vec<(u64, usize, MidiEvent)> {
+        vec![
+            (1_000_000, 1, MidiEvent::NoteOn(0, 60, 64)),
+            (1_000_000, 1, MidiEvent::NoteOn(0, 64, 64)),
+            (1_500_000, 1, MidiEvent::NoteOff(0, 60, 0)),
+            (1_500_000, 1, MidiEvent::NoteOn(0, 64, 0)),
+            (2_000_000, 1, MidiEvent::NoteOn(0, 67, 80)),
+        ]
+    }
+
+    #[test]
+    fn test_01() {
+        let mut scoring = Scoring::new(&events(), 100_000);
+        scoring.note_played(980_000, 60, 70);
+        scoring.note_played(1_040_000, 64, 60);
+        scoring.note_played(1_200_000, 65, 60);
+        scoring.update(2_200_000);
+        let summary = scoring.summary();
+        assert_eq!(summary.hits, 2);
+        assert_eq!(summary.missed, 1);
+        assert_eq!(summary.extra, 1);
+        assert_eq!(summary.mean_timing_error_us, 10_000);
+        assert_eq!(summary.mean_abs_timing_error_us, 30_000);
+        assert_eq!(summary.mean_velocity_error, 1);
+        assert_eq!(summary.mean_abs_velocity_error, 5);
+        assert_eq!(summary.accuracy_percent(), 50);
+    }
+
+    #[test]
+    fn test_02() {
+        let mut scoring = Scoring::new(&events(), 100_000);
+        scoring.note_played(1_000_000, 60, 64);
+        scoring.note_played(1_200_000, 65, 64);
+        // Jump over the second chord and back to the first one
+        scoring.seek(1_500_000);
+        scoring.note_played(2_000_000, 67, 80);
+        assert_eq!(scoring.summary().hits, 2);
+        assert_eq!(scoring.summary().missed, 0);
+        assert_eq!(scoring.summary().extra, 1);
+        // The results of the first pass are kept on the second one
+        scoring.seek(0);
+        assert_eq!(scoring.summary().hits, 2);
+        assert_eq!(scoring.summary().extra, 1);
+        scoring.note_played(1_000_000, 60, 64);
+        scoring.update(2_200_000);
+        let summary = scoring.summary();
+        assert_eq!(summary.hits, 3);
+        assert_eq!(summary.missed, 2);
+        assert_eq!(summary.extra, 1);
+        assert_eq!(scoring.to_json()["notes"].as_array().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_03() {
+        let mut scoring = Scoring::new(&events(), 100_000);
+        scoring.note_played(1_000_000, 60, 64);
+        scoring.note_played(1_000_000, 64, 64);
+        assert!(scoring.is_clean(500_000, 1_500_000));
+        assert!(!scoring.is_clean(500_000, 2_500_000));
+        scoring.note_played(1_200_000, 65, 64);
+        assert!(!scoring.is_clean(500_000, 1_500_000));
+        assert!(scoring.is_clean(0, 1_100_000));
+    }
+
+    #[test]
+    fn test_04() {
+        let mut scoring = Scoring::new(&events(), 100_000);
+        scoring.note_played(1_000_000, 60, 64);
+        scoring.update(1_800_000);
+        // Reload in the middle of the song with one more shown note
+        let mut show_events = events();
+        show_events.insert(0, (500_000, 2, MidiEvent::NoteOn(1, 48, 64)));
+        scoring.reload(&show_events, 1_800_000);
+        let summary = scoring.summary();
+        assert_eq!(summary.hits, 1);
+        assert_eq!(summary.missed, 1);
+        scoring.note_played(2_000_000, 67, 80);
+        scoring.update(2_200_000);
+        let summary = scoring.summary();
+        assert_eq!(summary.hits, 2);
+        assert_eq!(summary.missed, 1);
+        assert_eq!(summary.extra, 0);
+    }
+}
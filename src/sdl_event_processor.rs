@@ -1,10 +1,26 @@
 use log::*;
 
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, Mod};
 
 use crate::app_control::AppControl;
 
+fn track_of_key(keycode: Keycode) -> Option<usize> {
+    match keycode {
+        Keycode::Num0 => Some(0),
+        Keycode::Num1 => Some(1),
+        Keycode::Num2 => Some(2),
+        Keycode::Num3 => Some(3),
+        Keycode::Num4 => Some(4),
+        Keycode::Num5 => Some(5),
+        Keycode::Num6 => Some(6),
+        Keycode::Num7 => Some(7),
+        Keycode::Num8 => Some(8),
+        Keycode::Num9 => Some(9),
+        _ => None,
+    }
+}
+
 pub fn process_event(event: Event, control: &mut AppControl) -> bool {
     match event {
         Event::Window { win_event, .. } => {
@@ -63,6 +79,70 @@ pub fn process_event(event: Event, control: &mut AppControl) -> bool {
         } => {
             control.tune_up(true);
         }
+        Event::KeyDown {
+            keycode: Some(Keycode::A),
+            ..
+        } => {
+            control.set_loop_start();
+        }
+        Event::KeyDown {
+            keycode: Some(Keycode::B),
+            ..
+        } => {
+            control.set_loop_end();
+        }
+        Event::KeyDown {
+            keycode: Some(Keycode::L),
+            ..
+        } => {
+            control.clear_loop();
+        }
+        Event::KeyDown {
+            keycode: Some(Keycode::M),
+            ..
+        } => {
+            control.toggle_metronome();
+        }
+        Event::KeyDown {
+            keycode: Some(Keycode::P),
+            ..
+        } => {
+            control.panic();
+        }
+        Event::KeyDown {
+            keycode: Some(Keycode::H),
+            ..
+        } => {
+            control.toggle_hud();
+        }
+        Event::KeyDown {
+            keycode: Some(Keycode::PageUp),
+            ..
+        } => {
+            control.change_volume(true);
+        }
+        Event::KeyDown {
+            keycode: Some(Keycode::PageDown),
+            ..
+        } => {
+            control.change_volume(false);
+        }
+        Event::KeyDown {
+            keycode: Some(keycode),
+            keymod,
+            ..
+        } if track_of_key(keycode).is_some() => {
+            let trk_idx = track_of_key(keycode).unwrap();
+            if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) {
+                control.toggle_shown(trk_idx);
+            } else if keymod.intersects(Mod::LALTMOD | Mod::RALTMOD) {
+                control.toggle_played(trk_idx);
+            } else if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                control.toggle_solo(trk_idx);
+            } else {
+                control.toggle_mute(trk_idx);
+            }
+        }
         Event::MultiGesture {
             timestamp: _timestamp,
             touch_id: _touch_id,
@@ -0,0 +1,395 @@
+// Conversion between midi ticks and microseconds.
+//
+// All calculations are done in microseconds scaled by a divisor (ppqn for
+// metrical files, ticks per frame for timecode files). This way the time of
+// a tick is exact and rounding happens only once on the final conversion.
+// Otherwise a song with e.g. 497,000 us per quarter note drifts by seconds.
+
+/// Default tempo of 120 bpm as per midi standard, if no tempo event is given
+const DEFAULT_TEMPO: u32 = 500_000;
+
+/// Frame rate of a timecode based midi file as fraction (frames, seconds)
+fn fps_as_fraction(fps: &midly::Fps) -> (u64, u64) {
+    match fps {
+        midly::Fps::Fps24 => (24, 1),
+        midly::Fps::Fps25 => (25, 1),
+        // 29.97 fps drop frame timecode
+        midly::Fps::Fps29 => (30_000, 1_001),
+        midly::Fps::Fps30 => (30, 1),
+    }
+}
+
+#[derive(Clone, Debug)]
+struct TempoSegment {
+    tick: u64,
+    /// Start time of this segment multiplied by the divisor
+    scaled_us: u128,
+    /// Duration of one tick multiplied by the divisor
+    scaled_us_per_tick: u128,
+}
+
+#[derive(Clone, Debug)]
+struct MeterSegment {
+    tick: u64,
+    /// Bar number starting at this tick, the first bar is 0
+    bar: u64,
+    beats_per_bar: u64,
+    ticks_per_beat: u64,
+}
+impl MeterSegment {
+    fn ticks_per_bar(&self) -> u64 {
+        self.beats_per_bar * self.ticks_per_beat
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TempoMap {
+    divisor: u128,
+    metrical: bool,
+    segments: Vec<TempoSegment>,
+    meters: Vec<MeterSegment>,
+}
+impl TempoMap {
+    pub fn new(timing: &midly::Timing) -> TempoMap {
+        let (divisor, metrical, scaled_us_per_tick) = match timing {
+            // For metrical timing the tempo is in us per quarter note and
+            // the divisor is ticks per quarter note
+            midly::Timing::Metrical(ppqn) => {
+                (ppqn.as_int().max(1) as u128, true, DEFAULT_TEMPO as u128)
+            }
+            // For timecode every frame is divided into subframes and a tick
+            // is one subframe. Tempo meta events have to be ignored.
+            midly::Timing::Timecode(fps, subframes) => {
+                let (frames, seconds) = fps_as_fraction(fps);
+                (
+                    frames as u128 * (*subframes).max(1) as u128,
+                    false,
+                    1_000_000 * seconds as u128,
+                )
+            }
+        };
+        TempoMap {
+            divisor,
+            metrical,
+            segments: vec![TempoSegment {
+                tick: 0,
+                scaled_us: 0,
+                scaled_us_per_tick,
+            }],
+            // Default time signature is 4/4
+            meters: vec![MeterSegment {
+                tick: 0,
+                bar: 0,
+                beats_per_bar: 4,
+                ticks_per_beat: divisor as u64,
+            }],
+        }
+    }
+    /// Tempo changes have to be added in order of their tick position.
+    pub fn add_tempo(&mut self, tick: u64, tempo: u32) {
+        if !self.metrical {
+            return;
+        }
+        let last = self.segments.last().unwrap();
+        let tick = tick.max(last.tick);
+        let scaled_us = last.scaled_us + (tick - last.tick) as u128 * last.scaled_us_per_tick;
+        if last.tick == tick {
+            self.segments.pop();
+        }
+        self.segments.push(TempoSegment {
+            tick,
+            scaled_us,
+            scaled_us_per_tick: tempo as u128,
+        });
+    }
+    /// Time signature changes have to be added in order of their tick position.
+    /// The denominator is given as power of two like in the midi meta event.
+    /// A time signature change not on a bar line starts a new bar.
+    pub fn add_time_signature(&mut self, tick: u64, numerator: u8, denominator_pow: u8) {
+        if !self.metrical {
+            return;
+        }
+        let last = self.meters.last().unwrap();
+        let tick = tick.max(last.tick);
+        let dt = tick - last.tick;
+        let ticks_per_bar = last.ticks_per_bar();
+        let bar = last.bar + (dt + ticks_per_bar - 1) / ticks_per_bar;
+        if last.tick == tick {
+            self.meters.pop();
+        }
+        let ticks_per_beat = (4 * self.divisor as u64) >> denominator_pow;
+        self.meters.push(MeterSegment {
+            tick,
+            bar,
+            beats_per_bar: (numerator as u64).max(1),
+            ticks_per_beat: ticks_per_beat.max(1),
+        });
+    }
+    /// Returns the tick, at which the given bar starts. The first bar is 0.
+    /// Timecode based midi files have no bars.
+    pub fn bar_to_tick(&self, bar: u64) -> Option<u64> {
+        if !self.metrical {
+            return None;
+        }
+        let meter = self.meters.iter().rev().find(|m| m.bar <= bar).unwrap();
+        Some(meter.tick + (bar - meter.bar) * meter.ticks_per_bar())
+    }
+    /// Returns the beats per bar and the duration of a beat in us at the
+    /// given tick. Timecode based midi files have no beats.
+    pub fn beat_at(&self, tick: u64) -> Option<(u64, u64)> {
+        if !self.metrical {
+            return None;
+        }
+        let meter = self.meters.iter().rev().find(|m| m.tick <= tick).unwrap();
+        let seg = self.segments.iter().rev().find(|s| s.tick <= tick).unwrap();
+        let us_per_beat = meter.ticks_per_beat as u128 * seg.scaled_us_per_tick / self.divisor;
+        Some((meter.beats_per_bar, us_per_beat as u64))
+    }
+    /// Returns the ticks of all beats before end_tick and if the beat is the
+    /// first in its bar. Timecode based midi files have no beats.
+    pub fn beats(&self, end_tick: u64) -> Vec<(u64, bool)> {
+        let mut beats = vec![];
+        if !self.metrical {
+            return beats;
+        }
+        for (i, meter) in self.meters.iter().enumerate() {
+            let meter_end = self.meters.get(i + 1).map_or(end_tick, |next| next.tick);
+            let mut tick = meter.tick;
+            let mut beat = 0;
+            while tick < meter_end.min(end_tick) {
+                beats.push((tick, beat % meter.beats_per_bar == 0));
+                tick += meter.ticks_per_beat;
+                beat += 1;
+            }
+        }
+        beats
+    }
+    /// Returns the times in us of all beats before end_tick for drawing a
+    /// grid. The first beat of a bar carries the bar number counted from 1.
+    pub fn grid(&self, end_tick: u64) -> Vec<(u64, Option<u64>)> {
+        let mut bar = 0;
+        self.beats(end_tick)
+            .into_iter()
+            .map(|(tick, is_downbeat)| {
+                let opt_bar = if is_downbeat {
+                    bar += 1;
+                    Some(bar)
+                } else {
+                    None
+                };
+                (self.tick_to_us(tick), opt_bar)
+            })
+            .collect()
+    }
+    /// Returns the bar and the beat in the bar at the given tick, both
+    /// counted from 1. Timecode based midi files have no bars.
+    pub fn bar_beat_at(&self, tick: u64) -> Option<(u64, u64)> {
+        if !self.metrical {
+            return None;
+        }
+        let meter = self.meters.iter().rev().find(|m| m.tick <= tick).unwrap();
+        let beats = (tick - meter.tick) / meter.ticks_per_beat;
+        Some((
+            meter.bar + beats / meter.beats_per_bar + 1,
+            beats % meter.beats_per_bar + 1,
+        ))
+    }
+    /// Returns the tempo in quarter notes per minute at the given tick.
+    /// Timecode based midi files have no tempo.
+    pub fn bpm_at(&self, tick: u64) -> Option<f64> {
+        if !self.metrical {
+            return None;
+        }
+        let seg = self.segments.iter().rev().find(|s| s.tick <= tick).unwrap();
+        Some(60_000_000.0 / seg.scaled_us_per_tick.max(1) as f64)
+    }
+    pub fn tick_to_us(&self, tick: u64) -> u64 {
+        let seg = self
+            .segments
+            .iter()
+            .rev()
+            .find(|seg| seg.tick <= tick)
+            .unwrap();
+        let scaled_us = seg.scaled_us + (tick - seg.tick) as u128 * seg.scaled_us_per_tick;
+        (scaled_us / self.divisor) as u64
+    }
+    /// Returns the tick nearest to the given time.
+    pub fn us_to_tick(&self, time_us: u64) -> u64 {
+        let scaled_us = time_us as u128 * self.divisor;
+        let seg = self
+            .segments
+            .iter()
+            .rev()
+            .find(|seg| seg.scaled_us <= scaled_us)
+            .unwrap();
+        let dt = scaled_us - seg.scaled_us;
+        seg.tick + ((dt + seg.scaled_us_per_tick / 2) / seg.scaled_us_per_tick) as u64
+    }
+}
+
+/// Position in a song given either as time or as bar.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SongPosition {
+    Us(u64),
+    /// Bars are counted from 1 like in sheet music
+    Bar(u64),
+}
+impl SongPosition {
+    pub fn to_us(self, tempo_map: &TempoMap) -> Option<u64> {
+        match self {
+            SongPosition::Us(us) => Some(us),
+            SongPosition::Bar(bar) => tempo_map
+                .bar_to_tick(bar.max(1) - 1)
+                .map(|tick| tempo_map.tick_to_us(tick)),
+        }
+    }
+}
+/// Accepts seconds like "12.5", minutes and seconds like "1:02.5"
+/// or a bar like "b17"
+impl std::str::FromStr for SongPosition {
+    type Err = String;
+    fn from_str(s: &str) -> Result<SongPosition, String> {
+        let err = || format!("invalid song position: {}", s);
+        if let Some(bar) = s.strip_prefix('b') {
+            let bar = bar.parse::<u64>().map_err(|_| err())?;
+            return Ok(SongPosition::Bar(bar));
+        }
+        let mut seconds = 0.0;
+        for part in s.split(':') {
+            seconds = seconds * 60.0 + part.parse::<f64>().map_err(|_| err())?;
+        }
+        if seconds.is_nan() || seconds < 0.0 {
+            return Err(err());
+        }
+        Ok(SongPosition::Us((seconds * 1_000_000.0).round() as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::midi_container::MidiContainer;
+    use crate::tempo_map::SongPosition;
+    use crate::test_fixtures::metrical_smf;
+
+    #[test]
+    fn test_01() {
+        let buf = metrical_smf();
+        let smf_buf = midly::Smf::parse(&buf).unwrap();
+        let container = MidiContainer::from_buf(&smf_buf).unwrap();
+        let tempo_map = container.tempo_map();
+        assert_eq!(tempo_map.tick_to_us(0), 0);
+        assert_eq!(tempo_map.tick_to_us(1), 1294);
+        assert_eq!(tempo_map.tick_to_us(192_000), 248_500_000);
+        assert_eq!(tempo_map.tick_to_us(192_384), 248_750_000);
+    }
+
+    #[test]
+    fn test_02() {
+        let buf = metrical_smf();
+        let smf_buf = midly::Smf::parse(&buf).unwrap();
+        let container = MidiContainer::from_buf(&smf_buf).unwrap();
+        let tempo_map = container.tempo_map();
+        for tick in vec![0, 1, 383, 384, 191_999, 192_000, 192_001, 192_384].drain(..) {
+            assert_eq!(tempo_map.us_to_tick(tempo_map.tick_to_us(tick)), tick);
+        }
+        assert_eq!(tempo_map.us_to_tick(248_750_000), 192_384);
+    }
+
+    #[test]
+    fn test_03() {
+        let buf = metrical_smf();
+        let smf_buf = midly::Smf::parse(&buf).unwrap();
+        let container = MidiContainer::from_buf(&smf_buf).unwrap();
+        assert_eq!(
+            container
+                .iter()
+                .timed(&container.header().timing)
+                .map(|(time_us, _, _)| time_us)
+                .collect::<Vec<_>>(),
+            vec![0, 248_750_000, 248_750_000]
+        );
+    }
+
+    #[test]
+    fn test_04() {
+        assert_eq!("12.5".parse(), Ok(SongPosition::Us(12_500_000)));
+        assert_eq!("1:02.5".parse(), Ok(SongPosition::Us(62_500_000)));
+        assert_eq!("b17".parse(), Ok(SongPosition::Bar(17)));
+        assert!("x".parse::<SongPosition>().is_err());
+        assert!("-1".parse::<SongPosition>().is_err());
+
+        let buf = metrical_smf();
+        let smf_buf = midly::Smf::parse(&buf).unwrap();
+        let container = MidiContainer::from_buf(&smf_buf).unwrap();
+        let mut tempo_map = container.tempo_map();
+        // 4/4 at 497,000 us/qn
+        assert_eq!(SongPosition::Bar(1).to_us(&tempo_map), Some(0));
+        assert_eq!(SongPosition::Bar(3).to_us(&tempo_map), Some(3_976_000));
+        // 6/8 from the third bar on
+        tempo_map.add_time_signature(2 * 1536, 6, 3);
+        assert_eq!(tempo_map.bar_to_tick(3), Some(3072 + 1152));
+        // Change in the middle of a bar starts a new bar
+        tempo_map.add_time_signature(3072 + 1152 + 100, 3, 2);
+        assert_eq!(tempo_map.bar_to_tick(4), Some(3072 + 1152 + 100));
+        assert_eq!(tempo_map.bar_to_tick(5), Some(3072 + 1152 + 100 + 1152));
+    }
+
+    #[test]
+    fn test_05() {
+        let buf = metrical_smf();
+        let smf_buf = midly::Smf::parse(&buf).unwrap();
+        let container = MidiContainer::from_buf(&smf_buf).unwrap();
+        let mut tempo_map = container.tempo_map();
+        tempo_map.add_time_signature(1536, 3, 3);
+        assert_eq!(
+            tempo_map.beats(2304),
+            vec![
+                (0, true),
+                (384, false),
+                (768, false),
+                (1152, false),
+                (1536, true),
+                (1728, false),
+                (1920, false),
+                (2112, true),
+            ]
+        );
+        assert_eq!(tempo_map.beat_at(0), Some((4, 497_000)));
+        assert_eq!(tempo_map.beat_at(1536), Some((3, 248_500)));
+        assert_eq!(tempo_map.beat_at(192_000), Some((3, 125_000)));
+    }
+
+    #[test]
+    fn test_06() {
+        let buf = metrical_smf();
+        let smf_buf = midly::Smf::parse(&buf).unwrap();
+        let container = MidiContainer::from_buf(&smf_buf).unwrap();
+        let mut tempo_map = container.tempo_map();
+        tempo_map.add_time_signature(768, 2, 2);
+        assert_eq!(
+            tempo_map.grid(1920),
+            vec![
+                (0, Some(1)),
+                (497_000, None),
+                (994_000, Some(2)),
+                (1_491_000, None),
+                (1_988_000, Some(3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_07() {
+        let buf = metrical_smf();
+        let smf_buf = midly::Smf::parse(&buf).unwrap();
+        let container = MidiContainer::from_buf(&smf_buf).unwrap();
+        let mut tempo_map = container.tempo_map();
+        tempo_map.add_time_signature(1536, 3, 3);
+        assert_eq!(tempo_map.bar_beat_at(0), Some((1, 1)));
+        assert_eq!(tempo_map.bar_beat_at(1535), Some((1, 4)));
+        assert_eq!(tempo_map.bar_beat_at(1536), Some((2, 1)));
+        assert_eq!(tempo_map.bar_beat_at(1536 + 192 * 4), Some((3, 2)));
+        assert_eq!(tempo_map.bpm_at(0).map(|bpm| bpm.round()), Some(121.0));
+        assert_eq!(tempo_map.bpm_at(192_000), Some(240.0));
+    }
+}
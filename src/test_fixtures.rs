@@ -0,0 +1,84 @@
+//! Small midi files built in memory for the tests
+
+pub const END_OF_TRACK: [u8; 4] = [0x00, 0xff, 0x2f, 0x00];
+
+/// Midi file of the given format and division with the tracks, which
+/// have to contain the end of track event
+pub fn smf(format: u16, division: u16, tracks: &[Vec<u8>]) -> Vec<u8> {
+    let mut buf = b"MThd".to_vec();
+    buf.extend_from_slice(&6u32.to_be_bytes());
+    buf.extend_from_slice(&format.to_be_bytes());
+    buf.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&division.to_be_bytes());
+    for track in tracks.iter() {
+        buf.extend_from_slice(b"MTrk");
+        buf.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        buf.extend_from_slice(track);
+    }
+    buf
+}
+
+/// Single track midi file with 384 ticks per quarter note
+pub fn single_track(track: Vec<u8>) -> Vec<u8> {
+    smf(0, 384, &[track])
+}
+
+/// Single track midi file with 384 ticks per quarter note.
+/// Tempo changes from 497,000 to 250,000 us/qn after 500 quarter notes.
+pub fn metrical_smf() -> Vec<u8> {
+    single_track(vec![
+        0x00, 0xff, 0x51, 0x03, 0x07, 0x95, 0x68, // tempo 497000 us/qn
+        0x00, 0x90, 0x3c, 0x40, // note on
+        0x8b, 0xdc, 0x00, 0xff, 0x51, 0x03, 0x03, 0xd0, 0x90, // +192000: tempo 250000
+        0x83, 0x00, 0x80, 0x3c, 0x40, // +384 ticks: note off
+        0x00, 0xff, 0x2f, 0x00, // end of track
+    ])
+}
+
+/// Single track midi file with timecode division, a note of 1000 ticks
+/// and a tempo change in between, which has to be ignored.
+pub fn timecode_smf(fps: u8, subframes: u8) -> Vec<u8> {
+    let track = vec![
+        0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20, // tempo 500000 us/qn
+        0x00, 0x90, 0x3c, 0x40, // note on
+        0x83, 0x74, 0xff, 0x51, 0x03, 0x03, 0xd0, 0x90, // +500 ticks: tempo 250000
+        0x83, 0x74, 0x80, 0x3c, 0x40, // +500 ticks: note off
+        0x00, 0xff, 0x2f, 0x00, // end of track
+    ];
+    // Negative frames per second in the upper byte
+    let division = ((256 - fps as u16) << 8) | subframes as u16;
+    smf(0, division, &[track])
+}
+
+/// Format 1 file with 384 ticks per quarter note at 120 bpm in 4/4:
+/// - track 0 with tempo and time signature only
+/// - track 1 "upper" on "piano" with C4 and E4 of one quarter each on channel 0
+/// - track 2 "lower" with C3 of four quarters on channel 1, whose end of
+///   track comes one quarter after the note off
+pub fn two_hands() -> Vec<u8> {
+    let mut track_0 = vec![
+        0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20, // tempo 500000 us/qn
+        0x00, 0xff, 0x58, 0x04, 0x04, 0x02, 0x18, 0x08, // 4/4
+    ];
+    track_0.extend_from_slice(&END_OF_TRACK);
+    let mut track_1 = vec![0x00, 0xff, 0x03, 0x05];
+    track_1.extend_from_slice(b"upper");
+    track_1.extend_from_slice(&[0x00, 0xff, 0x04, 0x05]);
+    track_1.extend_from_slice(b"piano");
+    track_1.extend_from_slice(&[
+        0x00, 0xc0, 0x00, // program 0
+        0x00, 0x90, 0x3c, 0x40, // note on C4
+        0x83, 0x00, 0x80, 0x3c, 0x40, // +384: note off C4
+        0x00, 0x90, 0x40, 0x40, // note on E4
+        0x83, 0x00, 0x80, 0x40, 0x40, // +384: note off E4
+    ]);
+    track_1.extend_from_slice(&END_OF_TRACK);
+    let mut track_2 = vec![0x00, 0xff, 0x03, 0x05];
+    track_2.extend_from_slice(b"lower");
+    track_2.extend_from_slice(&[
+        0x00, 0x91, 0x30, 0x40, // note on C3
+        0x8c, 0x00, 0x91, 0x30, 0x00, // +1536: note on with velocity 0
+        0x83, 0x00, 0xff, 0x2f, 0x00, // +384: end of track
+    ]);
+    smf(1, 384, &[track_0, track_1, track_2])
+}
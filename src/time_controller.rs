@@ -5,6 +5,10 @@ pub struct RefPosition {
     pos_us: i64,
     at_instant: Option<Instant>,
     scaling_1000: u16,
+    /// Remaining beats of the count-in before playing starts
+    count_in_beats: u32,
+    /// Passes through the loop completed by jumping back to its start
+    loop_passes: u32,
 }
 impl RefPosition {
     pub fn set_pos_us(&mut self, pos_us: i64) {
@@ -45,6 +49,21 @@ impl RefPosition {
         self.advance_to_now();
         self.scaling_1000 = new_scale;
     }
+    pub fn get_scaling_1000(&self) -> u16 {
+        self.scaling_1000
+    }
+    pub fn set_count_in_beats(&mut self, beats: u32) {
+        self.count_in_beats = beats;
+    }
+    pub fn get_count_in_beats(&self) -> u32 {
+        self.count_in_beats
+    }
+    pub fn add_loop_pass(&mut self) {
+        self.loop_passes += 1;
+    }
+    pub fn get_loop_passes(&self) -> u32 {
+        self.loop_passes
+    }
     pub fn start(&mut self) {
         self.at_instant = Some(Instant::now());
     }
@@ -83,6 +102,15 @@ pub trait TimeListenerTrait {
     fn is_running(&self) -> bool {
         self.get_locked().unwrap().is_running()
     }
+    fn get_scaling_1000(&self) -> u16 {
+        self.get_locked().unwrap().get_scaling_1000()
+    }
+    fn get_count_in_beats(&self) -> u32 {
+        self.get_locked().unwrap().get_count_in_beats()
+    }
+    fn get_loop_passes(&self) -> u32 {
+        self.get_locked().unwrap().get_loop_passes()
+    }
     fn ms_till_pos(&self, next_pos_us: i64) -> Option<u32> {
         self.get_locked().unwrap().ms_till_pos(next_pos_us)
     }
@@ -108,6 +136,8 @@ impl TimeController {
                 pos_us: 0,
                 at_instant: None,
                 scaling_1000: 1000,
+                count_in_beats: 0,
+                loop_passes: 0,
             })),
         }
     }
@@ -122,6 +152,12 @@ impl TimeController {
     pub fn set_scaling_1000(&self, new_scale: u16) {
         self.get_locked().unwrap().set_scaling_1000(new_scale);
     }
+    pub fn set_count_in_beats(&self, beats: u32) {
+        self.get_locked().unwrap().set_count_in_beats(beats);
+    }
+    pub fn add_loop_pass(&self) {
+        self.get_locked().unwrap().add_loop_pass();
+    }
     pub fn start(&self) {
         self.get_locked().unwrap().start();
     }
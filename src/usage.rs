@@ -18,6 +18,18 @@ pub fn usage() -> clap::ArgMatches<'static> {
                     <+>             Faster
                     <->             Slower
                     <Space>         Pause/continue playing
+                    <A>             Set loop start at current position
+                    <B>             Set loop end at current position
+                    <L>             Clear loop
+                    <M>             Metronome on/off
+                    <P>             Panic: Silence and reset all used midi channels
+                    <H>             Show/hide heads-up display
+                    <0..9>          Mute/unmute track
+                    <Shift>+<0..9>  Solo/unsolo track
+                    <Ctrl>+<0..9>   Show/hide track
+                    <Alt>+<0..9>    Play/stop playing track
+                    <Page-Up>       Raise volume of shown tracks
+                    <Page-Down>     Lower volume of shown tracks
 
                 Gestures:
                     Two finger scrolling to move forward/backwards
@@ -38,12 +50,16 @@ pub fn usage() -> clap::ArgMatches<'static> {
         )
         .arg(
             Arg::with_name("play")
-                .required_unless("list")
+                .required_unless_one(&["list", "list_ports", "render", "video"])
                 .short("p")
                 .long("play-tracks")
                 .takes_value(true)
                 .multiple(true)
-                .help("Output these tracks as midi"),
+                .help(indoc!(
+                    "Output these tracks as midi given as <track>, ch<channel> or
+                    <track>:ch<channel>. The track is given by index or by name,
+                    which may contain wildcards like *piano*"
+                )),
         )
         .arg(
             Arg::with_name("show")
@@ -51,7 +67,115 @@ pub fn usage() -> clap::ArgMatches<'static> {
                 .long("show-tracks")
                 .takes_value(true)
                 .multiple(true)
-                .help("Show the tracks as falling notes"),
+                .help("Show the tracks as falling notes given like for play tracks"),
+        )
+        .arg(
+            Arg::with_name("input")
+                .short("i")
+                .long("input")
+                .requires("show")
+                .help("Show the keys pressed on midi input"),
+        )
+        .arg(
+            Arg::with_name("tolerance")
+                .long("tolerance")
+                .takes_value(true)
+                .default_value("150")
+                .help("Tolerance in ms for scoring notes played on midi input"),
+        )
+        .arg(
+            Arg::with_name("score_json")
+                .long("score-json")
+                .takes_value(true)
+                .requires("show")
+                .help("Write the score of the notes played on midi input as json file"),
+        )
+        .arg(
+            Arg::with_name("record")
+                .long("record")
+                .takes_value(true)
+                .help("Record midi input to this midi file together with the played song"),
+        )
+        .arg(
+            Arg::with_name("wait")
+                .short("w")
+                .long("wait")
+                .requires("show")
+                .help("Wait at every chord of the shown tracks until played on midi input"),
+        )
+        .arg(
+            Arg::with_name("no_play_shown")
+                .long("no-play-shown")
+                .help("Do not output the shown tracks as midi, so they can be played by hand"),
+        )
+        .arg(
+            Arg::with_name("loop")
+                .long("loop")
+                .takes_value(true)
+                .number_of_values(2)
+                .value_names(&["START", "END"])
+                .help(indoc!(
+                    "Loop between two positions given in seconds (12.5),
+                              minutes and seconds (1:02.5) or bars (b17)"
+                )),
+        )
+        .arg(
+            Arg::with_name("trainer")
+                .long("trainer")
+                .takes_value(true)
+                .value_name("START")
+                .help("Start playing at this speed in percent and raise it after each loop"),
+        )
+        .arg(
+            Arg::with_name("trainer_step")
+                .long("trainer-step")
+                .takes_value(true)
+                .default_value("5")
+                .help("Raise the speed by this many percent after each loop"),
+        )
+        .arg(
+            Arg::with_name("trainer_target")
+                .long("trainer-target")
+                .takes_value(true)
+                .default_value("100")
+                .help("Stop raising the speed at this percentage"),
+        )
+        .arg(
+            Arg::with_name("trainer_clean")
+                .long("trainer-clean")
+                .requires("trainer")
+                .requires("show")
+                .help("Raise the speed only after loops played without mistakes on midi input"),
+        )
+        .arg(
+            Arg::with_name("metronome")
+                .short("m")
+                .long("metronome")
+                .help("Start with metronome clicks on"),
+        )
+        .arg(
+            Arg::with_name("count_in")
+                .long("count-in")
+                .takes_value(true)
+                .value_name("BARS")
+                .default_value("0")
+                .help("Count in with metronome clicks before playing and after every jump"),
+        )
+        .arg(
+            Arg::with_name("downbeat_key")
+                .long("downbeat-key")
+                .takes_value(true)
+                .default_value("76")
+                .validator(validate_midi_key)
+                .help("Percussion key on channel 10 for the first beat of a bar"),
+        )
+        .arg(
+            Arg::with_name("beat_key")
+                .long("beat-key")
+                .takes_value(true)
+                .default_value("77")
+                .validator(validate_midi_key)
+                .help("Percussion key on channel 10 for the other beats"),
         )
         .arg(
             Arg::with_name("list")
@@ -59,6 +183,146 @@ pub fn usage() -> clap::ArgMatches<'static> {
                 .long("list-tracks")
                 .help("List the tracks in the midi file"),
         )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .help("Output format of the track listing"),
+        )
+        .arg(
+            Arg::with_name("list_ports")
+                .long("list-ports")
+                .help("List the midi input and output ports"),
+        )
+        .arg(
+            Arg::with_name("render")
+                .long("render")
+                .takes_value(true)
+                .requires("show")
+                .help("Render the shown tracks without window as png frames into this directory"),
+        )
+        .arg(
+            Arg::with_name("video")
+                .long("video")
+                .takes_value(true)
+                .requires("show")
+                .conflicts_with("render")
+                .help("Render the shown tracks without window as video file using ffmpeg"),
+        )
+        .arg(
+            Arg::with_name("lookahead")
+                .long("lookahead")
+                .takes_value(true)
+                .help("Seconds of notes visible in the rendered waterfall"),
+        )
+        .arg(
+            Arg::with_name("fps")
+                .long("fps")
+                .takes_value(true)
+                .default_value("30")
+                .help("Frames per second for rendering"),
+        )
+        .arg(
+            Arg::with_name("width")
+                .long("width")
+                .takes_value(true)
+                .default_value("1280")
+                .help("Width of the rendered frames"),
+        )
+        .arg(
+            Arg::with_name("height")
+                .long("height")
+                .takes_value(true)
+                .default_value("720")
+                .help("Height of the rendered frames"),
+        )
+        .arg(
+            Arg::with_name("at")
+                .long("at")
+                .takes_value(true)
+                .requires("render")
+                .help(indoc!(
+                    "Render only one frame at this position given in seconds (12.5),
+                              minutes and seconds (1:02.5) or bars (b17)"
+                )),
+        )
+        .arg(
+            Arg::with_name("port")
+                .long("port")
+                .takes_value(true)
+                .help("Midi output port given by index or part of its name"),
+        )
+        .arg(
+            Arg::with_name("virtual")
+                .long("virtual")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .value_name("name")
+                .conflicts_with("port")
+                .help(indoc!(
+                    "Create a virtual midi output port for synthesizers to connect to,
+                              named Rusthesia unless given like --virtual=Piano"
+                )),
+        )
+        .arg(
+            Arg::with_name("route")
+                .long("route")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Play a part on its own midi output port given as <part>=<port>"),
+        )
+        .arg(
+            Arg::with_name("input_port")
+                .long("input-port")
+                .takes_value(true)
+                .help("Midi input port given by index or part of its name"),
+        )
+        .arg(
+            Arg::with_name("note_names")
+                .long("note-names")
+                .takes_value(true)
+                .possible_values(&["english", "solfege"])
+                .help("Show the note names in the falling notes and on the keys"),
+        )
+        .arg(
+            Arg::with_name("fingers")
+                .long("fingers")
+                .help("Show finger numbers given as text or lyric events like '1' or '1 3 5'"),
+        )
+        .arg(
+            Arg::with_name("fingering")
+                .long("fingering")
+                .takes_value(true)
+                .help(indoc!(
+                    "Show finger numbers from a file with lines of <position> <note> <finger>,
+                              e.g. '1:02.5 F#3 2'"
+                )),
+        )
+        .arg(
+            Arg::with_name("font")
+                .long("font")
+                .takes_value(true)
+                .help("Font file for the note names and finger numbers"),
+        )
+        .arg(
+            Arg::with_name("no_hud")
+                .long("no-hud")
+                .help("Start with the heads-up display hidden"),
+        )
+        .arg(
+            Arg::with_name("keys")
+                .long("keys")
+                .takes_value(true)
+                .number_of_values(2)
+                .value_names(&["LEFT", "RIGHT"])
+                .conflicts_with("RD64")
+                .validator(validate_white_key)
+                .help("Range of the keyboard as midi keys of the most left and right white keys"),
+        )
         .arg(
             Arg::with_name("RD64")
                 .long("rd64")
@@ -67,7 +331,7 @@ pub fn usage() -> clap::ArgMatches<'static> {
         .arg(
             Arg::with_name("MIDI")
                 .help("Sets the midi file to use")
-                .required(true)
+                .required_unless("list_ports")
                 .index(1),
         )
         .arg(
@@ -85,3 +349,21 @@ pub fn usage() -> clap::ArgMatches<'static> {
         )
         .get_matches()
 }
+
+/// Keyboard range limits are white keys of the 88 note piano
+fn validate_white_key(s: String) -> Result<(), String> {
+    match s.parse::<u8>() {
+        Ok(key) if (21..=108).contains(&key) && ![1, 3, 6, 8, 10].contains(&(key % 12)) => Ok(()),
+        _ => Err(format!(
+            "'{}' is not a white key between 21 (A0) and 108 (C8)",
+            s
+        )),
+    }
+}
+
+fn validate_midi_key(s: String) -> Result<(), String> {
+    match s.parse::<u8>() {
+        Ok(key) if key <= 127 => Ok(()),
+        _ => Err(format!("'{}' is not a midi key between 0 and 127", s)),
+    }
+}